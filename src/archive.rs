@@ -0,0 +1,661 @@
+use crate::campaign::Campaign;
+use crate::commands::create_uuid;
+use crate::errors::AppError;
+use crate::migrations;
+use crate::storage::{Run, Storage, OUTPUT_FILE, OVERPASS_FILE, RUNS_DIR, TASKS_FILE};
+use crate::validation::FieldError;
+
+use chrono::prelude::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, read, remove_dir_all, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CAMPAIGN_FILE: &str = "campaign.json";
+const RUN_FILE: &str = "run.json";
+const ARCHIVE_FORMAT: u64 = 1;
+
+/// Largest manifest, campaign or run file read into memory.
+const MAX_DOCUMENT_BYTES: u64 = 16 * 1024 * 1024;
+/// Largest archive once unpacked, so a small gzip bomb can't fill the disk.
+const MAX_UNPACKED_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+
+/// Result files copied along with a campaign and each of its runs.
+const RESULT_FILES: &[&str] = &[OUTPUT_FILE, TASKS_FILE, OVERPASS_FILE];
+
+static IMPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Lists every other file of the archive with its SHA-256.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub format: u64,
+    pub uuid: String,
+    pub schema_version: u64,
+    pub exported_at: DateTime<Utc>,
+    pub files: BTreeMap<String, String>,
+}
+
+/// What to do when an imported campaign has the uuid of an existing one.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnCollision {
+    Fail,
+    NewUuid,
+}
+
+impl FromStr for OnCollision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnCollision::Fail),
+            "new-uuid" => Ok(OnCollision::NewUuid),
+            _ => Err(format!("Unknown option {}, expected fail or new-uuid", s)),
+        }
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn checksum(contents: &[u8]) -> String {
+    hex(&Sha256::digest(contents))
+}
+
+/// Copies an archive entry to `writer`, failing once more than `limit` bytes
+/// come out of it. Returns its size and checksum.
+fn copy_entry(
+    entry: impl Read,
+    name: &str,
+    limit: u64,
+    mut writer: impl Write,
+) -> Result<(u64, String), AppError> {
+    let mut reader = entry.take(limit + 1);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut size = 0;
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        size += read as u64;
+        if size > limit {
+            return Err(invalid(format!("{} is too large", name)));
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+
+    Ok((size, hex(&hasher.finalize())))
+}
+
+/// Whether `uuid` looks like one `create_uuid` made, safe to use as a folder name.
+fn is_uuid(uuid: &str) -> bool {
+    uuid.len() == 32 && uuid.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn invalid(message: String) -> AppError {
+    AppError::SerdeError(format!("Invalid archive - {}", message))
+}
+
+struct ArchiveWriter<W: Write> {
+    builder: tar::Builder<GzEncoder<W>>,
+    files: BTreeMap<String, String>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    fn append(&mut self, name: &str, contents: &[u8]) -> Result<(), AppError> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        header.set_cksum();
+
+        self.builder.append_data(&mut header, name, contents)?;
+        self.files.insert(name.to_string(), checksum(contents));
+
+        Ok(())
+    }
+
+    /// Adds the result files of a run, or of the campaign when `run` is none.
+    fn append_results(
+        &mut self,
+        storage: &dyn Storage,
+        uuid: &str,
+        run: Option<&str>,
+    ) -> Result<(), AppError> {
+        let prefix = match run {
+            Some(id) => format!("{}/{}/", RUNS_DIR, id),
+            None => String::new(),
+        };
+
+        for name in RESULT_FILES {
+            match storage.load_artifact(uuid, run, name) {
+                Ok(contents) => self.append(&format!("{}{}", prefix, name), &contents)?,
+                Err(AppError::NotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a campaign, its run history and their results as a gzipped tar,
+/// with the manifest last.
+pub fn export_campaign(
+    storage: &dyn Storage,
+    uuid: &str,
+    writer: impl Write,
+) -> Result<Manifest, AppError> {
+    if storage.is_campaign_running(uuid) {
+        return Err(AppError::Conflict(format!("Campaign {} is running", uuid)));
+    }
+
+    let campaign = storage.load_campaign(uuid)?;
+    let runs = storage.list_runs(uuid)?;
+
+    let mut archive = ArchiveWriter {
+        builder: tar::Builder::new(GzEncoder::new(writer, Compression::default())),
+        files: BTreeMap::new(),
+    };
+
+    archive.append(CAMPAIGN_FILE, &serde_json::to_vec_pretty(&campaign)?)?;
+    archive.append_results(storage, uuid, None)?;
+
+    for run in &runs {
+        let name = format!("{}/{}/{}", RUNS_DIR, run.id, RUN_FILE);
+        archive.append(&name, &serde_json::to_vec(run)?)?;
+        archive.append_results(storage, uuid, Some(&run.id))?;
+    }
+
+    let manifest = Manifest {
+        format: ARCHIVE_FORMAT,
+        uuid: uuid.to_string(),
+        schema_version: campaign.schema_version,
+        exported_at: Utc::now(),
+        files: archive.files.clone(),
+    };
+    archive.append(MANIFEST_FILE, &serde_json::to_vec_pretty(&manifest)?)?;
+
+    archive.builder.into_inner()?.finish()?;
+
+    Ok(manifest)
+}
+
+/// Checks an entry is one of the files an export writes, so nothing lands
+/// outside the campaign folder.
+fn entry_name(path: &Path) -> Result<String, AppError> {
+    let parts = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| match c {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()
+        .ok_or_else(|| invalid(format!("unexpected path {}", path.display())))?;
+
+    let expected = match parts.as_slice() {
+        [MANIFEST_FILE] | [CAMPAIGN_FILE] => true,
+        [name] => RESULT_FILES.contains(name),
+        [RUNS_DIR, id, name] => {
+            id.chars().all(|c| c.is_ascii_alphanumeric())
+                && (*name == RUN_FILE || RESULT_FILES.contains(name))
+        }
+        _ => false,
+    };
+
+    match expected {
+        true => Ok(parts.join("/")),
+        false => Err(invalid(format!("unexpected file {}", path.display()))),
+    }
+}
+
+/// Contents of an archive once checked against its manifest. Result files
+/// wait in a staging folder under the storage root.
+struct Unpacked {
+    manifest: Manifest,
+    campaign: Campaign,
+    runs: Vec<Run>,
+    staging: PathBuf,
+    results: Vec<String>,
+}
+
+fn unpack(reader: impl Read, staging: &Path) -> Result<Unpacked, AppError> {
+    let mut documents: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut checksums: BTreeMap<String, String> = BTreeMap::new();
+    let mut results = Vec::new();
+    let mut unpacked_bytes = 0;
+
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+
+        let name = entry_name(&entry.path()?)?;
+        let is_document =
+            name == MANIFEST_FILE || name == CAMPAIGN_FILE || name.ends_with(RUN_FILE);
+        let remaining = MAX_UNPACKED_BYTES - unpacked_bytes;
+
+        let (size, sum) = match is_document {
+            true => {
+                let mut contents = Vec::new();
+                let copied = copy_entry(
+                    &mut entry,
+                    &name,
+                    remaining.min(MAX_DOCUMENT_BYTES),
+                    &mut contents,
+                )?;
+                documents.insert(name.clone(), contents);
+                copied
+            }
+            false => {
+                let path = staging.join(&name);
+                create_dir_all(path.parent().unwrap())?;
+                let copied = copy_entry(&mut entry, &name, remaining, File::create(path)?)?;
+                results.push(name.clone());
+                copied
+            }
+        };
+
+        unpacked_bytes += size;
+        if name != MANIFEST_FILE {
+            checksums.insert(name, sum);
+        }
+    }
+
+    let manifest: Manifest = documents
+        .remove(MANIFEST_FILE)
+        .ok_or_else(|| invalid("no manifest".to_string()))
+        .and_then(|m| Ok(serde_json::from_slice(&m)?))?;
+
+    if manifest.format > ARCHIVE_FORMAT {
+        return Err(invalid(format!("unknown format {}", manifest.format)));
+    }
+    if !is_uuid(&manifest.uuid) {
+        return Err(invalid(format!("unexpected uuid {}", manifest.uuid)));
+    }
+    if manifest.files != checksums {
+        let mismatched = manifest
+            .files
+            .keys()
+            .chain(checksums.keys())
+            .find(|name| manifest.files.get(*name) != checksums.get(*name))
+            .cloned()
+            .unwrap_or_default();
+        return Err(invalid(format!("checksum mismatch on {}", mismatched)));
+    }
+
+    let campaign = documents
+        .remove(CAMPAIGN_FILE)
+        .ok_or_else(|| invalid("no campaign".to_string()))
+        .and_then(|c| migrations::from_str(&String::from_utf8_lossy(&c)))?;
+
+    // Only run.json files are left, each in the folder of its run.
+    let mut runs = documents
+        .iter()
+        .map(|(name, r)| {
+            let run = serde_json::from_slice::<Run>(r)?;
+            match name.split('/').nth(1) == Some(run.id.as_str()) {
+                true => Ok(run),
+                false => Err(invalid(format!("run {} stored in {}", run.id, name))),
+            }
+        })
+        .collect::<Result<Vec<Run>, AppError>>()?;
+    runs.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(Unpacked {
+        manifest,
+        campaign,
+        runs,
+        staging: staging.to_path_buf(),
+        results,
+    })
+}
+
+/// Stores the campaign of an archive made by `export_campaign` and returns
+/// its uuid, a new one if asked to on collision. The campaign must pass
+/// `validate`, as it would when created.
+pub fn import_campaign(
+    storage: &dyn Storage,
+    reader: impl Read,
+    on_collision: OnCollision,
+    validate: impl Fn(&Campaign) -> Result<(), Vec<FieldError>>,
+) -> Result<String, AppError> {
+    let staging = storage.root().join(format!(
+        ".import-{}-{}",
+        std::process::id(),
+        IMPORTS.fetch_add(1, Ordering::Relaxed)
+    ));
+    create_dir_all(&staging)?;
+
+    let imported = unpack(reader, &staging).and_then(|unpacked| {
+        validate(&unpacked.campaign).map_err(AppError::ValidationError)?;
+        store(storage, unpacked, on_collision)
+    });
+
+    if let Err(e) = remove_dir_all(&staging) {
+        warn!("Could not remove {} - {}", staging.display(), e);
+    }
+
+    imported
+}
+
+fn store(
+    storage: &dyn Storage,
+    unpacked: Unpacked,
+    on_collision: OnCollision,
+) -> Result<String, AppError> {
+    let uuid = match (storage.load_campaign(&unpacked.manifest.uuid), on_collision) {
+        (Err(AppError::NotFound), _) => unpacked.manifest.uuid.clone(),
+        (Ok(_c), OnCollision::NewUuid) => create_uuid(),
+        (Ok(_c), OnCollision::Fail) => {
+            return Err(AppError::Conflict(format!(
+                "Campaign {} already exists",
+                unpacked.manifest.uuid
+            )))
+        }
+        (Err(e), _) => return Err(e),
+    };
+
+    let campaign = Campaign {
+        uuid: Some(uuid.clone()),
+        ..unpacked.campaign.clone()
+    };
+    storage.save_campaign(campaign)?;
+
+    let stored = store_runs(storage, &uuid, &unpacked);
+    if stored.is_err() {
+        // Leave no campaign behind with only part of its runs.
+        if let Err(e) = storage.delete_campaign(&uuid) {
+            warn!("Could not remove partial import {} - {}", uuid, e);
+        }
+    }
+
+    stored.map(|()| uuid)
+}
+
+fn store_runs(storage: &dyn Storage, uuid: &str, unpacked: &Unpacked) -> Result<(), AppError> {
+    for name in &unpacked.results {
+        let contents = read(unpacked.staging.join(name))?;
+        let (run, file) = match name.strip_prefix(&format!("{}/", RUNS_DIR)) {
+            Some(path) => {
+                let (id, file) = path.split_once('/').unwrap();
+                (Some(id), file)
+            }
+            None => (None, name.as_str()),
+        };
+
+        storage.write_artifact(uuid, run, file, &contents)?;
+    }
+
+    for run in &unpacked.runs {
+        storage.save_run(uuid, run)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Status;
+    use crate::migrations::SCHEMA_VERSION;
+    use crate::source::Source;
+    use crate::storage::testing::{example_campaign, TempDir};
+    use crate::storage::{open, Backend};
+
+    const RUN_ID: &str = "20200101T000000Z";
+
+    fn stored_campaign(storage: &dyn Storage) -> String {
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid().set_status(Status::Finished))
+            .unwrap();
+        let run = serde_json::json!({
+            "id": RUN_ID,
+            "started_at": "2020-01-01T00:00:00Z",
+            "finished_at": "2020-01-01T00:01:00Z",
+            "refresh": false,
+            "stats": null,
+        });
+        storage
+            .save_run(&uuid, &serde_json::from_value(run).unwrap())
+            .unwrap();
+        storage
+            .write_artifact(&uuid, Some(RUN_ID), OUTPUT_FILE, b"{}")
+            .unwrap();
+
+        uuid
+    }
+
+    /// An archive of `files` whose manifest claims `uuid`, with `checksums`
+    /// changing what the manifest lists.
+    fn archive(
+        uuid: &str,
+        files: &[(&str, Vec<u8>)],
+        checksums: fn(&mut BTreeMap<String, String>),
+    ) -> Vec<u8> {
+        let mut archive = ArchiveWriter {
+            builder: tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default())),
+            files: BTreeMap::new(),
+        };
+        files
+            .iter()
+            .for_each(|(name, contents)| archive.append(name, contents).unwrap());
+
+        let mut files = archive.files.clone();
+        checksums(&mut files);
+        let manifest = Manifest {
+            format: ARCHIVE_FORMAT,
+            uuid: uuid.to_string(),
+            schema_version: SCHEMA_VERSION,
+            exported_at: Utc::now(),
+            files,
+        };
+        archive
+            .append(MANIFEST_FILE, &serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+
+        archive.builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn campaign_file() -> (&'static str, Vec<u8>) {
+        (
+            CAMPAIGN_FILE,
+            serde_json::to_vec(&example_campaign()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_collisions() {
+        let dir = TempDir::new("archive-collisions");
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let uuid = stored_campaign(storage.as_ref());
+
+        let mut exported = Vec::new();
+        export_campaign(storage.as_ref(), &uuid, &mut exported).unwrap();
+
+        match import_campaign(
+            storage.as_ref(),
+            exported.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        ) {
+            Err(AppError::Conflict(_)) => (),
+            other => panic!("Expected a conflict, got {:?}", other),
+        }
+
+        let copy = import_campaign(
+            storage.as_ref(),
+            exported.as_slice(),
+            OnCollision::NewUuid,
+            Campaign::validate,
+        )
+        .unwrap();
+        assert_ne!(copy, uuid);
+        assert_eq!(storage.list_runs(&copy).unwrap()[0].id, RUN_ID);
+        assert_eq!(
+            storage
+                .read_artifact(&copy, Some(RUN_ID), OUTPUT_FILE)
+                .unwrap(),
+            b"{}"
+        );
+
+        storage.delete_campaign(&uuid).unwrap();
+        let restored = import_campaign(
+            storage.as_ref(),
+            exported.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        )
+        .unwrap();
+        assert_eq!(restored, uuid);
+    }
+
+    #[test]
+    fn test_tampered_checksum() {
+        let dir = TempDir::new("archive-checksum");
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let uuid = create_uuid();
+
+        let tampered = archive(&uuid, &[campaign_file()], |files| {
+            files.insert(CAMPAIGN_FILE.to_string(), checksum(b"something else"));
+        });
+
+        match import_campaign(
+            storage.as_ref(),
+            tampered.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        ) {
+            Err(AppError::SerdeError(msg)) => assert!(msg.contains("checksum mismatch")),
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        }
+        assert!(storage.list_campaigns().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_paths_stay_in_storage() {
+        let dir = TempDir::new("archive-traversal");
+        let storage = open(Backend::File, &dir.path().join("storage")).unwrap();
+
+        let outside = archive("../outside", &[campaign_file()], |_files| ());
+        match import_campaign(
+            storage.as_ref(),
+            outside.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        ) {
+            Err(AppError::SerdeError(msg)) => assert!(msg.contains("unexpected uuid")),
+            other => panic!("Expected an invalid uuid, got {:?}", other),
+        }
+
+        let run = serde_json::json!({
+            "id": "../../escaped",
+            "started_at": "2020-01-01T00:00:00Z",
+            "finished_at": null,
+            "refresh": false,
+            "stats": null,
+        });
+        let run_file = format!("{}/{}/{}", RUNS_DIR, RUN_ID, RUN_FILE);
+        let escaping = archive(
+            &create_uuid(),
+            &[
+                campaign_file(),
+                (&run_file, serde_json::to_vec(&run).unwrap()),
+            ],
+            |_files| (),
+        );
+        match import_campaign(
+            storage.as_ref(),
+            escaping.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        ) {
+            Err(AppError::SerdeError(msg)) => assert!(msg.contains("stored in")),
+            other => panic!("Expected a misplaced run, got {:?}", other),
+        }
+
+        assert!(storage.list_campaigns().unwrap().is_empty());
+        let leftovers = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(leftovers, vec!["storage"]);
+    }
+
+    #[test]
+    fn test_entries_are_capped() {
+        let dir = TempDir::new("archive-bomb");
+        let storage = open(Backend::File, dir.path()).unwrap();
+
+        let huge = vec![b' '; MAX_DOCUMENT_BYTES as usize + 1];
+        let bomb = archive(&create_uuid(), &[(CAMPAIGN_FILE, huge)], |_files| ());
+        assert!(bomb.len() < MAX_DOCUMENT_BYTES as usize / 100);
+
+        match import_campaign(
+            storage.as_ref(),
+            bomb.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        ) {
+            Err(AppError::SerdeError(msg)) => assert!(msg.contains("campaign.json is too large")),
+            other => panic!("Expected an oversized entry, got {:?}", other),
+        }
+        assert!(storage.list_campaigns().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_imports_are_validated() {
+        let dir = TempDir::new("archive-validation");
+        let storage = open(Backend::File, dir.path()).unwrap();
+
+        let campaign = Campaign {
+            source: Some(Source::File {
+                path: PathBuf::from("/etc/passwd.osm"),
+            }),
+            ..example_campaign()
+        };
+        let local = archive(
+            &create_uuid(),
+            &[(CAMPAIGN_FILE, serde_json::to_vec(&campaign).unwrap())],
+            |_files| (),
+        );
+
+        let remote = |c: &Campaign| c.validate().and_then(|_| c.validate_remote_source());
+        match import_campaign(
+            storage.as_ref(),
+            local.as_slice(),
+            OnCollision::Fail,
+            remote,
+        ) {
+            Err(AppError::ValidationError(errors)) => assert_eq!(errors[0].field, "source"),
+            other => panic!("Expected a validation error, got {:?}", other),
+        }
+        assert!(storage.list_campaigns().unwrap().is_empty());
+
+        import_campaign(
+            storage.as_ref(),
+            local.as_slice(),
+            OnCollision::Fail,
+            Campaign::validate,
+        )
+        .unwrap();
+    }
+}
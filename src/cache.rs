@@ -0,0 +1,234 @@
+use crate::errors::AppError;
+use crate::storage::Storage;
+
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+const CACHE_DIR: &str = "cache";
+
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Overpass responses stored under the storage root, keyed by the hash of the query.
+#[derive(Clone, Debug)]
+pub struct OverpassCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl OverpassCache {
+    pub fn new(storage: &dyn Storage, ttl: u64) -> Self {
+        let path = storage.root().join(CACHE_DIR);
+
+        if let Err(e) = create_dir_all(&path) {
+            warn!("Could not create cache folder {}: {}", path.display(), e);
+        }
+
+        OverpassCache {
+            path,
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    pub fn key(query: &str) -> String {
+        Sha256::digest(query.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn entry(&self, key: &str) -> PathBuf {
+        self.path.join(format!("{}.osm", key))
+    }
+
+    fn age(path: &Path) -> Option<Duration> {
+        path.metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+    }
+
+    /// Path of the cached response for `key`, if present and younger than the TTL.
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = self.entry(key);
+
+        let age = Self::age(&path)?;
+
+        match age <= self.ttl {
+            true => {
+                info!("Overpass cache hit {}", key);
+                Some(path)
+            }
+            false => {
+                info!("Overpass cache entry {} expired", key);
+                None
+            }
+        }
+    }
+
+    /// Removes the entries older than the TTL, along with downloads interrupted
+    /// as long ago. Returns how many files went and their size.
+    pub fn prune(&self) -> Result<(usize, u64), AppError> {
+        let mut pruned = (0, 0);
+
+        for entry in read_dir(&self.path)? {
+            let path = entry?.path();
+            let expired = Self::age(&path).is_some_and(|age| age > self.ttl);
+
+            if expired && path.is_file() {
+                let size = path.metadata()?.len();
+                remove_file(&path)?;
+                pruned = (pruned.0 + 1, pruned.1 + size);
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    /// Wraps a response so it is stored in the cache as it is read. The entry only
+    /// becomes visible once the whole response went through, so readers never see
+    /// partial entries.
+    pub fn tee<R: Read>(&self, key: &str, reader: R) -> Result<CachingReader<R>, AppError> {
+        // Runs of the same query may download it at once, each into its own file.
+        let tmp_path = self.path.join(format!(
+            "{}.{}-{}.tmp",
+            key,
+            std::process::id(),
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::create(&tmp_path)?;
+
+        Ok(CachingReader {
+            inner: reader,
+            file: Some(file),
+            tmp_path,
+            path: self.entry(key),
+        })
+    }
+}
+
+pub struct CachingReader<R> {
+    inner: R,
+    file: Option<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl<R: Read> Read for CachingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        if read == 0 {
+            if let Some(file) = self.file.take() {
+                file.sync_all()?;
+                rename(&self.tmp_path, &self.path)?;
+            }
+        } else if let Some(ref mut file) = self.file {
+            file.write_all(&buf[..read])?;
+        }
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::TempDir;
+    use crate::storage::{open, Backend};
+    use std::fs::{read_to_string, write, OpenOptions};
+
+    fn cache(dir: &TempDir, ttl: u64) -> OverpassCache {
+        let storage = open(Backend::File, dir.path()).unwrap();
+        OverpassCache::new(storage.as_ref(), ttl)
+    }
+
+    /// Backdates a file as if written `secs` seconds ago.
+    fn age_by(path: &Path, secs: u64) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn store(cache: &OverpassCache, key: &str, contents: &str) {
+        let mut reader = cache.tee(key, contents.as_bytes()).unwrap();
+        let mut read = String::new();
+        reader.read_to_string(&mut read).unwrap();
+        assert_eq!(read, contents);
+    }
+
+    #[test]
+    fn test_keys() {
+        let key = OverpassCache::key("[out:xml];node(1);out;");
+
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, OverpassCache::key("[out:xml];node(1);out;"));
+        assert_ne!(key, OverpassCache::key("[out:xml];node(2);out;"));
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let dir = TempDir::new("cache-hit");
+        let cache = cache(&dir, 60);
+        let key = OverpassCache::key("query");
+
+        assert_eq!(cache.get(&key), None);
+        store(&cache, &key, "<osm/>");
+
+        let path = cache.get(&key).unwrap();
+        assert_eq!(read_to_string(path).unwrap(), "<osm/>");
+        assert_eq!(cache.get(&OverpassCache::key("other query")), None);
+    }
+
+    #[test]
+    fn test_partial_downloads_are_not_cached() {
+        let dir = TempDir::new("cache-partial");
+        let cache = cache(&dir, 60);
+        let key = OverpassCache::key("query");
+
+        let mut reader = cache.tee(&key, "<osm/>".as_bytes()).unwrap();
+        let mut buf = [0; 2];
+        reader.read_exact(&mut buf).unwrap();
+
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = TempDir::new("cache-expiry");
+        let cache = cache(&dir, 60);
+        let key = OverpassCache::key("query");
+        store(&cache, &key, "<osm/>");
+
+        age_by(&cache.entry(&key), 30);
+        assert!(cache.get(&key).is_some());
+
+        age_by(&cache.entry(&key), 120);
+        assert_eq!(cache.get(&key), None);
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = TempDir::new("cache-prune");
+        let cache = cache(&dir, 60);
+        let (fresh, expired) = (OverpassCache::key("fresh"), OverpassCache::key("expired"));
+        store(&cache, &fresh, "<osm/>");
+        store(&cache, &expired, "<osm></osm>");
+        age_by(&cache.entry(&expired), 120);
+        // A download interrupted long ago.
+        let interrupted = cache.path.join(format!("{}.1-0.tmp", expired));
+        write(&interrupted, "<os").unwrap();
+        age_by(&interrupted, 120);
+
+        assert_eq!(cache.prune().unwrap(), (2, 14));
+
+        assert!(cache.get(&fresh).is_some());
+        assert!(!cache.entry(&expired).exists());
+        assert!(!interrupted.exists());
+        assert_eq!(cache.prune().unwrap(), (0, 0));
+    }
+}
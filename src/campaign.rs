@@ -1,32 +1,108 @@
+use geo::algorithm::bounding_rect::BoundingRect;
 use geo::algorithm::centroid::Centroid;
+use geo::algorithm::chamberlain_duquette_area::ChamberlainDuquetteArea;
 
-use geo_types::{Geometry, GeometryCollection, MultiPoint, Point};
+use geo_types::{Geometry, GeometryCollection, MultiPoint};
 use serde::{Deserialize, Serialize};
 
 use crate::parser::parse;
 
-use crate::overpass::Overpass;
-use crate::storage::LocalStorage;
+use crate::cache::OverpassCache;
+use crate::errors::AppError;
+use crate::goals::{goal_progress, Goal, GoalProgress};
+use crate::migrations::SCHEMA_VERSION;
+use crate::source::{from_campaign, CountingReader, DataSource, Source, TeeReader};
+use crate::storage::{Run, SharedStorage, Storage, OUTPUT_FILE, OVERPASS_FILE};
+use crate::tasks::{build_tasks, TaskGrid};
 
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration as StdDuration, Instant};
+
+const PROGRESS_INTERVAL: StdDuration = StdDuration::from_secs(2);
 
 use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
 
 use uuid::Uuid;
 
 use log::{error, info};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct User {
     name: String,
     pub id: i64,
 }
 
+impl User {
+    pub fn new(name: &str, id: i64) -> Self {
+        User {
+            name: name.to_string(),
+            id,
+        }
+    }
+}
+
+/// What a user may do on a campaign, from least to most.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,
+    Manager,
+    Owner,
+}
+
+/// Private campaigns are only shown to their owner and collaborators.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collaborator {
+    pub user: User,
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Status {
     Created,
     Running,
     Finished,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Fetching,
+    Parsing,
+    Saving,
+}
+
+/// How far the current run got, refreshed while it goes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Progress {
+    pub phase: Phase,
+    pub bytes_downloaded: u64,
+    pub elements_parsed: u64,
+}
+
+impl Progress {
+    pub fn new(phase: Phase, bytes_downloaded: u64, elements_parsed: u64) -> Self {
+        Progress {
+            phase,
+            bytes_downloaded,
+            elements_parsed,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -35,14 +111,93 @@ pub struct Campaign {
     pub geometry_types: Vec<String>,
     pub tags: HashMap<String, SearchTag>,
     pub geom: geojson::GeoJson,
+    pub description: Option<String>,
+    pub organisation: Option<String>,
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub goals: Vec<Goal>,
+    /// Progress towards the goals as of the last finished run.
+    pub goal_progress: Option<Vec<GoalProgress>>,
+    pub task_grid: Option<TaskGrid>,
     pub uuid: Option<String>,
     pub created_at: Option<DateTime<Utc>>,
     pub updated_at: Option<DateTime<Utc>>,
+    /// Bumped on every write, sent as the ETag of the campaign.
+    #[serde(default)]
+    pub version: u64,
+    /// Layout of the stored document, see `migrations`. Storage reads go
+    /// through the migrations, so a missing version here means a new campaign.
+    #[serde(default = "current_schema_version")]
+    pub schema_version: u64,
     pub user: Option<User>,
     pub status: Option<Status>,
+    pub source: Option<Source>,
+    pub start_date: Option<DateTime<Utc>>,
+    pub end_date: Option<DateTime<Utc>>,
+    pub run_interval_hours: Option<i64>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub progress: Option<Progress>,
+    pub tasking_manager: Option<TaskingManager>,
+    #[serde(default)]
+    pub collaborators: Vec<Collaborator>,
+}
+
+/// Tasking Manager project a campaign was imported from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskingManager {
+    pub project_id: i64,
+    pub changeset_comment: Option<String>,
+}
+
+fn current_schema_version() -> u64 {
+    SCHEMA_VERSION
 }
 
 impl Campaign {
+    pub fn new(
+        name: String,
+        geometry_types: Vec<String>,
+        tags: HashMap<String, SearchTag>,
+        geom: geojson::GeoJson,
+    ) -> Self {
+        Campaign {
+            name,
+            geometry_types,
+            tags,
+            geom,
+            description: None,
+            organisation: None,
+            hashtags: Vec::new(),
+            visibility: Visibility::Public,
+            categories: Vec::new(),
+            goals: Vec::new(),
+            goal_progress: None,
+            task_grid: None,
+            uuid: None,
+            created_at: None,
+            updated_at: None,
+            version: 0,
+            schema_version: SCHEMA_VERSION,
+            user: None,
+            status: None,
+            source: None,
+            start_date: None,
+            end_date: None,
+            run_interval_hours: None,
+            last_run: None,
+            error: None,
+            progress: None,
+            tasking_manager: None,
+            collaborators: Vec::new(),
+        }
+    }
+
     pub fn set_uuid(self) -> Self {
         let uuid = Uuid::new_v4();
         let mut buffer = Uuid::encode_buffer();
@@ -54,10 +209,64 @@ impl Campaign {
         }
     }
 
-    pub fn is_creator(&self, user: &User) -> bool {
-        let campaign_user = self.user.as_ref().unwrap();
+    /// The campaign user is the owner, everyone else gets their collaborator role.
+    pub fn role_of(&self, user: &User) -> Option<Role> {
+        if self.user.as_ref() == Some(user) {
+            return Some(Role::Owner);
+        }
+
+        self.collaborators
+            .iter()
+            .find(|c| &c.user == user)
+            .map(|c| c.role)
+    }
 
-        campaign_user.id == user.id && campaign_user.name == user.name
+    pub fn has_role(&self, user: &User, role: Role) -> bool {
+        self.role_of(user).is_some_and(|r| r >= role)
+    }
+
+    /// Public campaigns are visible to anyone, private ones to members only.
+    pub fn is_visible_to(&self, user: Option<&User>) -> bool {
+        match self.visibility {
+            Visibility::Public => true,
+            Visibility::Private => user.is_some_and(|u| self.has_role(u, Role::Viewer)),
+        }
+    }
+
+    /// Adds a collaborator, or changes the role of an existing one.
+    pub fn set_collaborator(self, collaborator: Collaborator) -> Self {
+        let mut collaborators = self.collaborators;
+        collaborators.retain(|c| c.user.id != collaborator.user.id);
+        collaborators.push(collaborator);
+
+        Campaign {
+            collaborators,
+            ..self
+        }
+    }
+
+    pub fn remove_collaborator(self, user_id: i64) -> Self {
+        let mut collaborators = self.collaborators;
+        collaborators.retain(|c| c.user.id != user_id);
+
+        Campaign {
+            collaborators,
+            ..self
+        }
+    }
+
+    /// Makes `user` the owner, the previous owner stays on as manager.
+    pub fn transfer_ownership(self, user: User) -> Self {
+        let previous = self.user.clone();
+        let campaign = self.remove_collaborator(user.id).set_user(user);
+
+        match previous {
+            Some(previous) => campaign.set_collaborator(Collaborator {
+                user: previous,
+                role: Role::Manager,
+            }),
+            None => campaign,
+        }
     }
 
     pub fn set_created_date(self) -> Self {
@@ -76,6 +285,22 @@ impl Campaign {
         }
     }
 
+    /// Marks a write, bumping the version and the updated date.
+    pub fn next_version(self) -> Self {
+        Campaign {
+            version: self.version + 1,
+            ..self.set_updated_date()
+        }
+    }
+
+    /// Fails unless `expected` is none or the current version.
+    pub fn check_version(&self, expected: Option<u64>) -> Result<(), AppError> {
+        match expected {
+            Some(v) if v != self.version => Err(AppError::StaleVersion(self.version)),
+            _ => Ok(()),
+        }
+    }
+
     pub fn set_user(self, user: User) -> Self {
         Campaign {
             user: Some(user),
@@ -90,18 +315,113 @@ impl Campaign {
         }
     }
 
+    pub fn set_error(self, error: Option<String>) -> Self {
+        Campaign { error, ..self }
+    }
+
+    pub fn set_progress<P: Into<Option<Progress>>>(self, progress: P) -> Self {
+        Campaign {
+            progress: progress.into(),
+            ..self
+        }
+    }
+
+    pub fn set_tasking_manager(self, tasking_manager: TaskingManager) -> Self {
+        Campaign {
+            tasking_manager: Some(tasking_manager),
+            ..self
+        }
+    }
+
+    pub fn set_goal_progress(self, goal_progress: Vec<GoalProgress>) -> Self {
+        Campaign {
+            goal_progress: Some(goal_progress),
+            ..self
+        }
+    }
+
+    pub fn set_last_run(self, date: DateTime<Utc>) -> Self {
+        Campaign {
+            last_run: Some(date),
+            ..self
+        }
+    }
+
+    /// Whether the campaign is within its dates and either recurring with its
+    /// interval elapsed, or waiting for its start date to run the first time.
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        if let Some(Status::Running) = self.status {
+            return false;
+        }
+
+        if self.start_date.is_some_and(|d| now < d) || self.end_date.is_some_and(|d| now > d) {
+            return false;
+        }
+
+        match (self.last_run, self.run_interval_hours) {
+            (Some(last), Some(hours)) => last + Duration::hours(hours) <= now,
+            (Some(_last), None) => false,
+            (None, hours) => hours.is_some() || self.start_date.is_some(),
+        }
+    }
+
+    /// Whether the start date is still to come, the scheduler runs the
+    /// campaign then.
+    pub fn starts_later(&self) -> bool {
+        self.start_date.is_some_and(|d| Utc::now() < d)
+    }
+
+    /// Approximate area covered by the campaign polygons, in square kilometres.
+    pub fn area_km2(&self) -> f64 {
+        let collection: GeometryCollection<f64> = match geojson::quick_collection(&self.geom) {
+            Ok(c) => c,
+            Err(_e) => return 0.0,
+        };
+
+        let area: f64 = collection
+            .iter()
+            .map(|g| match g {
+                Geometry::Polygon(p) => p.chamberlain_duquette_unsigned_area(),
+                Geometry::MultiPolygon(mp) => mp
+                    .iter()
+                    .map(|p| p.chamberlain_duquette_unsigned_area())
+                    .sum(),
+                _ => 0.0,
+            })
+            .sum();
+
+        area / 1_000_000.0
+    }
+
+    /// Bounding box of the campaign area as (min lon, min lat, max lon, max lat).
+    pub fn bbox(&self) -> Option<(f64, f64, f64, f64)> {
+        let collection: GeometryCollection<f64> = geojson::quick_collection(&self.geom).ok()?;
+
+        collection
+            .bounding_rect()
+            .map(|r| (r.min().x, r.min().y, r.max().x, r.max().y))
+    }
+
+    /// Replaces the geometry by the centroid of its polygons. A geometry with
+    /// no polygon to take one from is kept as is.
     pub fn centroid_as_geom(self) -> Self {
-        let collection: GeometryCollection<f64> = geojson::quick_collection(&self.geom).unwrap();
+        let collection: GeometryCollection<f64> = match geojson::quick_collection(&self.geom) {
+            Ok(c) => c,
+            Err(_e) => return self,
+        };
 
         let centroids: MultiPoint<f64> = collection
             .iter()
-            .map(|f| match f {
-                Geometry::Polygon(p) => p.centroid().unwrap(),
-                _ => panic!("Geom not supported"),
+            .filter_map(|f| match f {
+                Geometry::Polygon(p) => p.centroid(),
+                _ => None,
             })
             .collect();
 
-        let point = Point::from(centroids.centroid().unwrap());
+        let point = match centroids.centroid() {
+            Some(c) => c,
+            None => return self,
+        };
         let geometry = geojson::Geometry::new(geojson::Value::from(&point));
 
         let geom = geojson::GeoJson::from(geometry);
@@ -117,64 +437,334 @@ pub struct SearchTag {
 }
 
 pub struct CampaignRun {
-    source: Overpass,
-    storage: LocalStorage,
+    source: Box<dyn DataSource>,
+    storage: SharedStorage,
+    refresh: bool,
+    archive: bool,
     tags: HashMap<String, SearchTag>,
     geometry_types: Vec<String>,
+    goals: Vec<Goal>,
+    task_grid: Option<TaskGrid>,
+    geom: geojson::GeoJson,
     uuid: String,
 }
 
 impl CampaignRun {
-    pub fn new(campaign: Campaign, storage: LocalStorage) -> Self {
-        CampaignRun {
-            source: Overpass::new(campaign.clone()),
+    pub fn new(
+        campaign: Campaign,
+        storage: SharedStorage,
+        cache: OverpassCache,
+        overpass_url: &str,
+    ) -> Result<Self, AppError> {
+        Ok(CampaignRun {
+            source: from_campaign(&campaign, cache, overpass_url)?,
             storage: storage,
+            refresh: false,
+            archive: false,
             tags: campaign.tags.clone(),
             geometry_types: campaign.geometry_types.clone(),
+            goals: campaign.goals.clone(),
+            task_grid: campaign.task_grid.clone(),
+            geom: campaign.geom.clone(),
             uuid: campaign.uuid.unwrap(),
-        }
+        })
     }
 
-    fn overpass(&self) -> String {
+    /// Skip cached responses and always fetch fresh data.
+    pub fn set_refresh(self, refresh: bool) -> Self {
+        CampaignRun { refresh, ..self }
+    }
+
+    /// Keep a copy of the raw response as overpass.xml next to the results.
+    pub fn set_archive(self, archive: bool) -> Self {
+        CampaignRun { archive, ..self }
+    }
+
+    /// Saves the stored campaign back with `change` applied.
+    fn update(&self, change: impl Fn(Campaign) -> Campaign) -> Result<(), AppError> {
         self.storage
-            .path
-            .join(self.uuid.clone())
-            .join("overpass.xml")
-            .display()
-            .to_string()
+            .modify_campaign(&self.uuid, None, Box::new(change))
+            .map(|_c| ())
     }
 
-    fn json(&self) -> String {
+    fn set_progress(&self, progress: Progress) {
+        if let Err(err) = self.update(|c| c.set_progress(progress.clone())) {
+            error!("Could not update progress of {} - {:?}", self.uuid, err);
+        }
+    }
+
+    fn execute(&self, run: &Run) -> Result<(), AppError> {
+        let run_id = run.id.clone();
+        let run_path = self.storage.run_path(&self.uuid, &run.id);
+        let json_path = run_path.join(OUTPUT_FILE).display().to_string();
+
+        let reader = self.source.fetch(self.refresh)?;
+        let reader: Box<dyn Read> = match self.archive {
+            true => Box::new(TeeReader::new(
+                reader,
+                File::create(run_path.join(OVERPASS_FILE))?,
+            )),
+            false => reader,
+        };
+
+        let bytes = Rc::new(Cell::new(0));
+        let reader = CountingReader::new(reader, bytes.clone());
+
+        let mut last_update = Instant::now();
+        let stats = parse(
+            reader,
+            &json_path,
+            &self.tags,
+            &self.geometry_types,
+            &self.goals,
+            |elements| {
+                if last_update.elapsed() >= PROGRESS_INTERVAL {
+                    last_update = Instant::now();
+                    self.set_progress(Progress::new(Phase::Parsing, bytes.get(), elements));
+                }
+            },
+        )?;
+
+        self.set_progress(Progress::new(Phase::Saving, bytes.get(), 0));
+        self.storage.store_run_files(&self.uuid, &run_id)?;
+        let goals = goal_progress(&self.goals, &self.tags, &stats);
         self.storage
-            .path
-            .join(self.uuid.clone())
-            .join("output.json")
-            .display()
-            .to_string()
+            .save_run(&self.uuid, &run.clone().finish(stats, goals.clone()))?;
+
+        if let Some(task_grid) = &self.task_grid {
+            let results = self.storage.load_results(&self.uuid, Some(&run_id))?;
+            let tasks = build_tasks(task_grid, &self.geom, results)?;
+            self.storage.save_tasks(&self.uuid, &run_id, &tasks)?;
+        }
+
+        self.update(|c| c.set_goal_progress(goals.clone()))?;
+
+        Ok(())
     }
 
     pub fn run(&self) {
-        let campaign = self.storage.load_campaign(&self.uuid).unwrap();
-        let new_campaign = campaign.clone().set_status(Status::Running);
-        match self.storage.update_campaign(campaign, new_campaign) {
+        match self.update(|c| {
+            c.set_status(Status::Running)
+                .set_last_run(Utc::now())
+                .set_error(None)
+                .set_progress(Progress::new(Phase::Fetching, 0, 0))
+        }) {
             Ok(()) => info!("Set status to running"),
             Err(_err) => error!("Could not update campaign status to running"),
         };
         info!("Started campaign run - {}", self.uuid);
 
-        let xml_path = self.overpass();
-        let json_path = self.json();
+        let result = self
+            .storage
+            .create_run(&self.uuid, self.refresh)
+            .and_then(|run| {
+                // A panic fails the run rather than taking the worker thread down.
+                let result = panic::catch_unwind(AssertUnwindSafe(|| self.execute(&run)))
+                    .unwrap_or_else(|panic| {
+                        let message = match panic.downcast_ref::<&str>() {
+                            Some(m) => m.to_string(),
+                            None => panic
+                                .downcast_ref::<String>()
+                                .cloned()
+                                .unwrap_or_else(|| "Unknown error".to_string()),
+                        };
+                        Err(AppError::RunError(message))
+                    });
+
+                if let Err(ref err) = result {
+                    let failed = run.clone().fail(err.to_string());
+                    if let Err(e) = self.storage.save_run(&self.uuid, &failed) {
+                        error!("Could not record failed run {} - {}", run.id, e);
+                    }
+                }
+                result
+            });
+
+        match result {
+            Ok(()) => {
+                match self.update(|c| c.set_status(Status::Finished).set_progress(None)) {
+                    Ok(()) => info!("Set status to finished"),
+                    Err(_err) => error!("Could not update campaign status to finished"),
+                };
+                info!("Finished campaign run - {}", self.uuid);
+            }
+            Err(err) => {
+                error!("Campaign run {} failed - {}", self.uuid, err);
+                set_failed(self.storage.as_ref(), &self.uuid, &err);
+            }
+        }
+    }
+}
 
-        self.source.fetch_data(&xml_path);
+fn set_failed(storage: &dyn Storage, uuid: &str, err: &AppError) {
+    let message = err.to_string();
+    let failed = storage.modify_campaign(
+        uuid,
+        None,
+        Box::new(|c| {
+            c.set_status(Status::Failed)
+                .set_error(Some(message.clone()))
+                .set_progress(None)
+        }),
+    );
+
+    if let Err(e) = failed {
+        error!("Could not update campaign status to failed - {}", e);
+    }
+}
 
-        parse(&xml_path, &json_path, &self.tags, &self.geometry_types);
+/// Loads a campaign and runs it. A campaign whose run can't even be set up,
+/// e.g. for an invalid source, is marked failed rather than left waiting.
+pub fn run_campaign(
+    storage: SharedStorage,
+    cache: OverpassCache,
+    overpass_url: &str,
+    uuid: &str,
+    refresh: bool,
+    archive: bool,
+) -> Result<(), AppError> {
+    let run = storage
+        .load_campaign(uuid)
+        .and_then(|campaign| CampaignRun::new(campaign, storage.clone(), cache, overpass_url));
+
+    match run {
+        Ok(run) => {
+            run.set_refresh(refresh).set_archive(archive).run();
+            Ok(())
+        }
+        Err(AppError::NotFound) => Err(AppError::NotFound),
+        Err(err) => {
+            error!("Could not start a run of campaign {} - {}", uuid, err);
+            set_failed(storage.as_ref(), uuid, &err);
+            Err(err)
+        }
+    }
+}
 
-        let campaign = self.storage.load_campaign(&self.uuid).unwrap();
-        let new_campaign = campaign.clone().set_status(Status::Finished);
-        match self.storage.update_campaign(campaign, new_campaign) {
-            Ok(()) => info!("Set status to finished"),
-            Err(_err) => error!("Could not update campaign status to finished"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::{example_campaign, TempDir};
+    use crate::storage::{open, Backend};
+    use crate::tasks::GridShape;
+    use geojson::GeoJson;
+    use std::fs::{copy, create_dir_all};
+
+    /// Stores `campaign` with examples/overpass.xml as its source and runs it.
+    fn run(dir: &TempDir, campaign: Campaign) -> (SharedStorage, String) {
+        create_dir_all(dir.path()).unwrap();
+        let osm = dir.path().join("data.osm");
+        copy("examples/overpass.xml", &osm).unwrap();
+
+        let storage = open(Backend::File, &dir.path().join("storage")).unwrap();
+        let campaign = Campaign {
+            source: Some(Source::File { path: osm }),
+            ..campaign
         };
-        info!("Finished campaign run - {}", self.uuid);
+        let uuid = storage
+            .save_campaign(campaign.set_uuid().set_status(Status::Created))
+            .unwrap();
+
+        let campaign = storage.load_campaign(&uuid).unwrap();
+        let cache = OverpassCache::new(storage.as_ref(), 0);
+        CampaignRun::new(campaign, storage.clone(), cache, "")
+            .unwrap()
+            .run();
+
+        (storage, uuid)
+    }
+
+    #[test]
+    fn test_runs_without_matches() {
+        let dir = TempDir::new("run-no-matches");
+        let mut tags = HashMap::new();
+        tags.insert(
+            "nothing".to_string(),
+            SearchTag {
+                values: vec!["here".to_string()],
+                secondary: None,
+            },
+        );
+        let campaign = Campaign {
+            tags,
+            task_grid: Some(TaskGrid {
+                shape: GridShape::Square,
+                size_km: 0.5,
+            }),
+            ..example_campaign()
+        };
+
+        let (storage, uuid) = run(&dir, campaign);
+
+        let campaign = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(campaign.error, None);
+        assert_eq!(campaign.status, Some(Status::Finished));
+
+        let runs = storage.list_runs(&uuid).unwrap();
+        assert!(runs[0].finished_at.is_some());
+        match storage.load_results(&uuid, None).unwrap() {
+            GeoJson::FeatureCollection(c) => assert!(c.features.is_empty()),
+            other => panic!("Expected a feature collection, got {:?}", other),
+        }
+
+        let tasks = storage.load_tasks(&uuid, None).unwrap();
+        assert!(!tasks.is_empty());
+        assert!(tasks.iter().all(|t| t.features == 0));
+    }
+
+    fn start(dir: &TempDir, campaign: Campaign) -> (SharedStorage, String, Result<(), AppError>) {
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let uuid = storage
+            .save_campaign(campaign.set_uuid().set_status(Status::Created))
+            .unwrap();
+
+        let cache = OverpassCache::new(storage.as_ref(), 0);
+        let result = run_campaign(storage.clone(), cache, "", &uuid, false, false);
+
+        (storage, uuid, result)
+    }
+
+    #[test]
+    fn test_failed_runs_are_recorded() {
+        let dir = TempDir::new("run-failing-source");
+        let campaign = Campaign {
+            source: Some(Source::File {
+                path: dir.path().join("missing.osm"),
+            }),
+            ..example_campaign()
+        };
+
+        let (storage, uuid, result) = start(&dir, campaign);
+        assert!(result.is_ok());
+
+        let campaign = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(campaign.status, Some(Status::Failed));
+        assert!(campaign.error.is_some());
+
+        let runs = storage.list_runs(&uuid).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert!(runs[0].finished_at.is_some());
+        assert_eq!(runs[0].error, campaign.error);
+        assert!(!runs[0].succeeded());
+        assert!(!storage.is_campaign_running(&uuid));
+    }
+
+    #[test]
+    fn test_campaigns_failing_to_start_are_marked_failed() {
+        let dir = TempDir::new("run-failing-start");
+        let campaign = Campaign {
+            geom: GeoJson::Geometry(geojson::Geometry::new(geojson::Value::Point(vec![
+                0.0, 0.0,
+            ]))),
+            ..example_campaign()
+        };
+
+        let (storage, uuid, result) = start(&dir, campaign);
+        assert!(result.is_err());
+
+        let campaign = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(campaign.status, Some(Status::Failed));
+        assert!(campaign.error.is_some());
+        assert!(!storage.is_campaign_running(&uuid));
     }
 }
@@ -1,10 +1,22 @@
-use crate::campaign::{Campaign, CampaignRun};
+use crate::archive::{self, OnCollision};
+use crate::cache::OverpassCache;
+use crate::campaign::{run_campaign, Campaign, Status, User};
+use crate::diff::diff_runs;
 use crate::errors::AppError;
-use crate::storage::LocalStorage;
+use crate::overpass::{Overpass, QueryPreview};
+use crate::retention::{collect_garbage, RetentionPolicy};
+use crate::scheduler::run_scheduler;
+use crate::server::sign_token;
+use crate::storage::SharedStorage;
+use crate::templates::Templates;
+use crate::tm::TmProject;
+
+use geojson::GeoJson;
 
 use log::{error, info};
 use serde_json;
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 use chrono::prelude::{DateTime, Utc};
@@ -13,6 +25,13 @@ pub enum CommandResult {
     GetCampaign(String),
     CreateCampaign(String),
     CreateStorage(String),
+    Query(String),
+    Diff(String),
+    Migrate(String),
+    Export(String),
+    Import(String),
+    Gc(String),
+    Token(i64),
     Serve,
 }
 
@@ -22,22 +41,131 @@ impl CommandResult {
             CommandResult::CreateCampaign(uuid) => format!("CAMPAIGN::CREATE::OK::{}", uuid),
             CommandResult::GetCampaign(uuid) => format!("CAMPAIGN::GET::OK::{}", uuid),
             CommandResult::CreateStorage(storage) => format!("STORAGE::CREATE::OK::{}", storage),
+            CommandResult::Query(campaign) => format!("QUERY::OK::{}", campaign),
+            CommandResult::Diff(summary) => format!("DIFF::OK::{}", summary),
+            CommandResult::Migrate(summary) => format!("MIGRATE::OK::{}", summary),
+            CommandResult::Export(path) => format!("CAMPAIGN::EXPORT::OK::{}", path),
+            CommandResult::Import(uuid) => format!("CAMPAIGN::IMPORT::OK::{}", uuid),
+            CommandResult::Gc(summary) => format!("GC::OK::{}", summary),
+            CommandResult::Token(id) => format!("TOKEN::OK::{}", id),
             CommandResult::Serve => format!("SERVER::OK"),
         }
     }
 }
 
-pub fn load_campaign(uuid: &str, storage: LocalStorage) -> Result<CommandResult, AppError> {
-    let campaign = storage
-        .load_campaign(uuid)
-        .map_err(|err| AppError::IOError(err.to_string()))?;
-
-    let run = CampaignRun::new(campaign, storage);
-    run.run();
+pub fn load_campaign(
+    uuid: &str,
+    storage: SharedStorage,
+    cache: OverpassCache,
+    overpass_url: &str,
+    refresh: bool,
+    debug: bool,
+) -> Result<CommandResult, AppError> {
+    run_campaign(storage, cache, overpass_url, uuid, refresh, debug).map_err(|err| match err {
+        AppError::NotFound => AppError::IOError(err.to_string()),
+        err => err,
+    })?;
 
     Ok(CommandResult::GetCampaign(uuid.to_string()))
 }
 
+/// Re-runs recurring campaigns as they become due, without the HTTP server.
+pub fn scheduler(
+    storage: SharedStorage,
+    cache: OverpassCache,
+    overpass_url: &str,
+    poll: u64,
+    debug: bool,
+) -> Result<CommandResult, AppError> {
+    run_scheduler(storage.as_ref(), poll, |uuid| {
+        let run = run_campaign(
+            storage.clone(),
+            cache.clone(),
+            overpass_url,
+            &uuid,
+            true,
+            debug,
+        );
+
+        if let Err(err) = run {
+            error!("Could not load campaign {} - {:?}", uuid, err);
+        }
+    })
+}
+
+/// Upgrades every stored campaign to the current schema version.
+pub fn migrate(storage: SharedStorage) -> Result<CommandResult, AppError> {
+    let report = storage.migrate_campaigns()?;
+
+    report
+        .migrated
+        .iter()
+        .for_each(|uuid| info!("Campaign {} migrated", uuid));
+    report
+        .unreadable
+        .iter()
+        .for_each(|(uuid, err)| error!("Could not read campaign {} - {}", uuid, err));
+
+    Ok(CommandResult::Migrate(report.summary()))
+}
+
+/// Deletes and compresses run files as `policy` says, and expired cache entries.
+pub fn gc(
+    storage: SharedStorage,
+    cache: OverpassCache,
+    policy: RetentionPolicy,
+) -> Result<CommandResult, AppError> {
+    let report = collect_garbage(storage.as_ref(), &cache, &policy)?;
+
+    report
+        .failed
+        .iter()
+        .for_each(|(uuid, err)| error!("Could not collect campaign {} - {}", uuid, err));
+
+    Ok(CommandResult::Gc(report.summary()))
+}
+
+/// Writes a campaign with its runs and results to a .tar.gz archive.
+pub fn export_campaign(
+    uuid: &str,
+    output: &Path,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let exported = File::create(output)
+        .map_err(AppError::from)
+        .and_then(|file| archive::export_campaign(storage.as_ref(), uuid, file));
+
+    if let Err(e) = exported {
+        let _ = std::fs::remove_file(output);
+        return Err(e);
+    }
+
+    Ok(CommandResult::Export(output.display().to_string()))
+}
+
+/// Stores the campaign of an archive made by `export-campaign`.
+pub fn import_campaign(
+    path: &Path,
+    on_collision: OnCollision,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let uuid = archive::import_campaign(
+        storage.as_ref(),
+        File::open(path)?,
+        on_collision,
+        Campaign::validate,
+    )?;
+
+    Ok(CommandResult::Import(uuid))
+}
+
+/// Prints an API token for a user, to hand out to them.
+pub fn token(name: &str, id: i64, secret_key: &str) -> Result<CommandResult, AppError> {
+    println!("{}", sign_token(&User::new(name, id), secret_key)?);
+
+    Ok(CommandResult::Token(id))
+}
+
 pub fn create_uuid() -> String {
     let uuid = Uuid::new_v4();
     let mut buffer = Uuid::encode_buffer();
@@ -45,26 +173,97 @@ pub fn create_uuid() -> String {
     uuid
 }
 
-pub fn create_campaign(path: &str, storage: LocalStorage) -> Result<CommandResult, AppError> {
-    let uuid = create_uuid();
-
+pub fn create_campaign(path: &str, storage: SharedStorage) -> Result<CommandResult, AppError> {
     let file = File::open(path)?;
     let campaign: Result<Campaign, AppError> =
         serde_json::from_reader(file).map_err(|err| AppError::SerdeError(err.to_string()));
 
-    let mut campaign = campaign?;
+    save_new_campaign(campaign?, storage)
+}
+
+/// Creates a campaign from a template and a GeoJSON file with its area.
+pub fn create_from_template(
+    template: &str,
+    name: &str,
+    geometry_path: &str,
+    templates: &Templates,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let template = templates.get(template).ok_or(AppError::NotFound)?;
+    let geom: GeoJson = serde_json::from_reader(File::open(geometry_path)?)?;
+
+    save_new_campaign(template.to_campaign(name.to_string(), geom), storage)
+}
+
+/// Creates a campaign from a HOT Tasking Manager project export.
+pub fn import_tm(
+    path: &str,
+    templates: &Templates,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let project = TmProject::from_reader(File::open(path)?)?;
+
+    save_new_campaign(project.into_campaign(templates)?, storage)
+}
+
+fn save_new_campaign(
+    mut campaign: Campaign,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let uuid = create_uuid();
+    campaign.validate().map_err(AppError::ValidationError)?;
 
     let utc: DateTime<Utc> = Utc::now();
     campaign.uuid = Some(uuid.clone());
     campaign.created_at = Some(utc);
+    campaign.status = Some(Status::Created);
 
-    match storage
-        .save_campaign(campaign)
-        .map_err(|err| AppError::SerdeError(err.to_string()))
-    {
-        Ok(v) => info!("Campaign {} created successfully", v),
-        Err(err) => error!("{}", err),
-    };
+    let uuid = storage.save_campaign(campaign)?;
+    info!("Campaign {} created successfully", uuid);
 
     Ok(CommandResult::CreateCampaign(uuid))
 }
+
+/// Prints the Overpass QL of a campaign, given either a campaign JSON file or a stored uuid.
+pub fn query_campaign(
+    campaign: &str,
+    pretty: bool,
+    execute: Option<PathBuf>,
+    storage: SharedStorage,
+    overpass_url: &str,
+) -> Result<CommandResult, AppError> {
+    let loaded: Campaign = match Path::new(campaign).is_file() {
+        true => serde_json::from_reader(File::open(campaign)?)?,
+        false => storage.load_campaign(campaign)?,
+    };
+    loaded.validate().map_err(AppError::ValidationError)?;
+
+    let preview = QueryPreview::new(&loaded, pretty)?;
+    println!("{}", preview.query);
+    println!("Area: {:.3} km2", preview.area_km2);
+
+    if let Some(path) = execute {
+        Overpass::new(loaded)?
+            .set_url(overpass_url)
+            .fetch_data(&path.display().to_string(), true)?;
+        info!("Overpass response saved to {}", path.display());
+    }
+
+    Ok(CommandResult::Query(campaign.to_string()))
+}
+
+/// Prints the GeoJSON diff between two runs of a campaign.
+pub fn diff_campaign(
+    uuid: &str,
+    from: &str,
+    to: &str,
+    storage: SharedStorage,
+) -> Result<CommandResult, AppError> {
+    let (collection, summary) = diff_runs(storage.as_ref(), uuid, from, to)?;
+    println!("{}", collection);
+
+    Ok(CommandResult::Diff(format!(
+        "+{}::-{}::completed:{}::regressed:{}::modified:{}",
+        summary.added, summary.removed, summary.completed, summary.regressed, summary.modified
+    )))
+}
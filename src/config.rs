@@ -0,0 +1,184 @@
+use crate::errors::AppError;
+use crate::overpass::OVERPASS_URL;
+
+use serde::Deserialize;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Read from the working directory when no other file is given.
+pub const CONFIG_FILE: &str = "mc2.toml";
+
+/// Key tokens were signed with before it could be configured, only accepted
+/// in dev mode.
+pub const DEFAULT_SECRET_KEY: &str = "pleasechangeme1234";
+
+/// Settings from `mc2.toml`, each overridden by its `MC2_*` variable.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server listens on, `MC2_BIND`.
+    pub bind: String,
+    /// `MC2_PORT`.
+    pub port: u16,
+    /// Key signing the API tokens, `MC2_SECRET_KEY`.
+    pub secret_key: String,
+    /// Campaigns the server runs at once, `MC2_WORKERS`.
+    pub workers: usize,
+    /// Overpass interpreter campaigns are fetched from, `MC2_OVERPASS_URL`.
+    pub overpass_url: String,
+    /// Storage folder when none is given on the command line, `MC2_STORAGE`.
+    pub storage: Option<PathBuf>,
+    /// Origins browsers may call the API from, `*` for any. `MC2_CORS_ORIGINS`
+    /// takes them separated by commas.
+    pub cors_origins: Vec<String>,
+    /// Log filter such as `info` or `mc2=debug`, `MC2_LOG_LEVEL`. `RUST_LOG`
+    /// still wins when set.
+    pub log_level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            secret_key: DEFAULT_SECRET_KEY.to_string(),
+            workers: 1,
+            overpass_url: OVERPASS_URL.to_string(),
+            storage: None,
+            cors_origins: Vec::new(),
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+fn parse_var<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, AppError> {
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_e| AppError::ConfigError(format!("Invalid {} {}", name, value)))
+        })
+        .transpose()
+}
+
+impl Config {
+    /// Reads `path`, or `mc2.toml` when it exists and no path is given, then
+    /// applies the environment.
+    pub fn load(path: Option<&Path>) -> Result<Self, AppError> {
+        let default_path = Path::new(CONFIG_FILE);
+
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if default_path.is_file() => Self::from_file(default_path)?,
+            None => Config::default(),
+        };
+
+        config.with_env(|name| env::var(name).ok())
+    }
+
+    fn from_file(path: &Path) -> Result<Self, AppError> {
+        let contents = read_to_string(path).map_err(|e| {
+            AppError::ConfigError(format!("Could not read {} - {}", path.display(), e))
+        })?;
+
+        toml::from_str(&contents)
+            .map_err(|e| AppError::ConfigError(format!("Invalid {} - {}", path.display(), e)))
+    }
+
+    /// Overrides the settings whose variable `var` finds.
+    fn with_env(self, var: impl Fn(&str) -> Option<String>) -> Result<Self, AppError> {
+        let cors_origins = var("MC2_CORS_ORIGINS").map(|origins| {
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|o| !o.is_empty())
+                .map(String::from)
+                .collect()
+        });
+
+        Ok(Config {
+            bind: var("MC2_BIND").unwrap_or(self.bind),
+            port: parse_var(&var, "MC2_PORT")?.unwrap_or(self.port),
+            secret_key: var("MC2_SECRET_KEY").unwrap_or(self.secret_key),
+            workers: parse_var(&var, "MC2_WORKERS")?.unwrap_or(self.workers),
+            overpass_url: var("MC2_OVERPASS_URL").unwrap_or(self.overpass_url),
+            storage: var("MC2_STORAGE").map(PathBuf::from).or(self.storage),
+            cors_origins: cors_origins.unwrap_or(self.cors_origins),
+            log_level: var("MC2_LOG_LEVEL").unwrap_or(self.log_level),
+        })
+    }
+
+    /// Refuses to serve with the well known default secret, anyone could sign
+    /// tokens with it, unless running in dev mode.
+    pub fn check_server(&self, dev: bool) -> Result<(), AppError> {
+        if self.secret_key == DEFAULT_SECRET_KEY && !dev {
+            return Err(AppError::ConfigError(
+                "Refusing to serve with the default secret key, set secret_key in mc2.toml or MC2_SECRET_KEY, or pass --dev".to_string(),
+            ));
+        }
+        if self.secret_key.is_empty() {
+            return Err(AppError::ConfigError("The secret key is empty".to_string()));
+        }
+        if self.workers == 0 {
+            return Err(AppError::ConfigError(
+                "At least one worker is needed".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_environment_overrides_file() {
+        let file: Config = toml::from_str(
+            r#"
+            port = 9000
+            workers = 4
+            secret_key = "from-file"
+            cors_origins = ["https://a.example"]
+            "#,
+        )
+        .unwrap();
+
+        let env: HashMap<&str, &str> = [
+            ("MC2_PORT", "9100"),
+            ("MC2_CORS_ORIGINS", "https://b.example, https://c.example"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let config = file
+            .with_env(|name| env.get(name).map(|v| v.to_string()))
+            .unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1");
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.secret_key, "from-file");
+        assert_eq!(
+            config.cors_origins,
+            vec!["https://b.example", "https://c.example"]
+        );
+        assert!(config.check_server(false).is_ok());
+
+        assert!(Config::default().check_server(false).is_err());
+        assert!(Config::default().check_server(true).is_ok());
+        assert!(Config::default()
+            .with_env(|name| match name {
+                "MC2_WORKERS" => Some("many".to_string()),
+                _ => None,
+            })
+            .is_err());
+    }
+}
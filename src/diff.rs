@@ -0,0 +1,328 @@
+use crate::errors::AppError;
+use crate::parser::Stats;
+use crate::storage::Storage;
+
+use geojson::{Feature, FeatureCollection, GeoJson, Value};
+use serde::Serialize;
+use serde_json::{to_value, Map};
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug)]
+pub struct DiffSummary {
+    pub from: String,
+    pub to: String,
+    pub added: usize,
+    pub removed: usize,
+    pub completed: usize,
+    pub regressed: usize,
+    /// Features whose geometry or tags changed, still as complete as before.
+    pub modified: usize,
+    /// Change of every counter, `to` minus `from`.
+    pub stats: Stats,
+}
+
+/// Nodes and ways can share ids, outputs only tell them apart by geometry.
+fn feature_key(feature: &Feature) -> Option<String> {
+    let id = feature.properties.as_ref()?.get("id")?;
+    let element = match feature.geometry.as_ref().map(|g| &g.value) {
+        Some(Value::Point(_)) => "node",
+        _ => "way",
+    };
+
+    Some(format!("{}/{}", element, id))
+}
+
+/// A feature is complete when every search tag it matched has all its secondary tags.
+pub fn is_complete(feature: &Feature) -> bool {
+    feature
+        .properties
+        .as_ref()
+        .and_then(|p| p.get("stats"))
+        .and_then(|s| s.as_object())
+        .is_none_or(|stats| {
+            stats.values().all(|tag_errors| {
+                tag_errors
+                    .get("completeness")
+                    .and_then(|c| c.as_f64())
+                    .is_none_or(|c| c >= 1.0)
+            })
+        })
+}
+
+fn split_results(results: GeoJson) -> Result<(HashMap<String, Feature>, Stats), AppError> {
+    let collection = match results {
+        GeoJson::FeatureCollection(c) => c,
+        _ => {
+            return Err(AppError::SerdeError(
+                "Results must be a FeatureCollection".to_string(),
+            ))
+        }
+    };
+
+    let stats = collection
+        .foreign_members
+        .as_ref()
+        .and_then(|m| m.get("properties"))
+        .map(|p| serde_json::from_value(p.clone()))
+        .transpose()?
+        .unwrap_or_default();
+
+    let features = collection
+        .features
+        .into_iter()
+        .filter_map(|f| feature_key(&f).map(|key| (key, f)))
+        .collect();
+
+    Ok((features, stats))
+}
+
+fn counter_delta(from: &HashMap<String, i64>, to: &HashMap<String, i64>) -> HashMap<String, i64> {
+    from.keys()
+        .chain(to.keys())
+        .map(|k| {
+            let delta = to.get(k).unwrap_or(&0) - from.get(k).unwrap_or(&0);
+            (k.clone(), delta)
+        })
+        .collect()
+}
+
+fn nested_delta(
+    from: &HashMap<String, HashMap<String, i64>>,
+    to: &HashMap<String, HashMap<String, i64>>,
+) -> HashMap<String, HashMap<String, i64>> {
+    let empty = HashMap::new();
+
+    from.keys()
+        .chain(to.keys())
+        .map(|k| {
+            let delta = counter_delta(from.get(k).unwrap_or(&empty), to.get(k).unwrap_or(&empty));
+            (k.clone(), delta)
+        })
+        .collect()
+}
+
+fn stats_delta(from: &Stats, to: &Stats) -> Stats {
+    Stats {
+        feature_counts: counter_delta(&from.feature_counts, &to.feature_counts),
+        contributors: nested_delta(&from.contributors, &to.contributors),
+        attributes_count: nested_delta(&from.attributes_count, &to.attributes_count),
+        completeness_count: nested_delta(&from.completeness_count, &to.completeness_count),
+    }
+}
+
+fn is_modified(previous: &Feature, feature: &Feature) -> bool {
+    previous.geometry != feature.geometry || previous.properties != feature.properties
+}
+
+fn with_change(feature: Feature, change: &str) -> Feature {
+    let mut properties = feature.properties.unwrap_or_default();
+    properties.insert("change".to_string(), to_value(change).unwrap());
+
+    Feature {
+        properties: Some(properties),
+        ..feature
+    }
+}
+
+/// Features added, removed, completed, regressed or otherwise modified between
+/// two runs, plus a summary with the change of every counter.
+pub fn diff_runs(
+    storage: &dyn Storage,
+    uuid: &str,
+    from: &str,
+    to: &str,
+) -> Result<(GeoJson, DiffSummary), AppError> {
+    diff_results(
+        from,
+        to,
+        storage.load_results(uuid, Some(from))?,
+        storage.load_results(uuid, Some(to))?,
+    )
+}
+
+fn diff_results(
+    from: &str,
+    to: &str,
+    old: GeoJson,
+    new: GeoJson,
+) -> Result<(GeoJson, DiffSummary), AppError> {
+    let (mut old, old_stats) = split_results(old)?;
+    let (new, new_stats) = split_results(new)?;
+
+    let mut features = Vec::new();
+    let (mut added, mut completed, mut regressed, mut modified) = (0, 0, 0, 0);
+
+    for (key, feature) in new {
+        match old.remove(&key) {
+            None => {
+                added += 1;
+                features.push(with_change(feature, "added"));
+            }
+            Some(previous) => match (is_complete(&previous), is_complete(&feature)) {
+                (false, true) => {
+                    completed += 1;
+                    features.push(with_change(feature, "completed"));
+                }
+                (true, false) => {
+                    regressed += 1;
+                    features.push(with_change(feature, "regressed"));
+                }
+                _ if is_modified(&previous, &feature) => {
+                    modified += 1;
+                    features.push(with_change(feature, "modified"));
+                }
+                _ => (),
+            },
+        }
+    }
+
+    // Whatever is left of the old run is gone from the new one.
+    let removed = old.len();
+    features.extend(old.into_values().map(|f| with_change(f, "removed")));
+
+    let summary = DiffSummary {
+        from: from.to_string(),
+        to: to.to_string(),
+        added,
+        removed,
+        completed,
+        regressed,
+        modified,
+        stats: stats_delta(&old_stats, &new_stats),
+    };
+
+    let mut foreign_members = Map::new();
+    foreign_members.insert("summary".to_string(), to_value(&summary)?);
+
+    let collection = GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: Some(foreign_members),
+    });
+
+    Ok((collection, summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geojson::Geometry;
+    use serde_json::json;
+
+    fn feature(id: i64, coordinates: Vec<f64>, completeness: f64) -> Feature {
+        let properties = json!({
+            "id": id,
+            "stats": {"building": {"completeness": completeness}},
+        });
+
+        Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::Point(coordinates))),
+            id: None,
+            properties: properties.as_object().cloned(),
+            foreign_members: None,
+        }
+    }
+
+    fn collection(features: Vec<Feature>) -> GeoJson {
+        GeoJson::FeatureCollection(FeatureCollection {
+            bbox: None,
+            features,
+            foreign_members: None,
+        })
+    }
+
+    /// Change of every feature in the diff by id.
+    fn changes(diff: GeoJson) -> HashMap<i64, String> {
+        let features = match diff {
+            GeoJson::FeatureCollection(c) => c.features,
+            other => panic!("Expected a feature collection, got {:?}", other),
+        };
+
+        features
+            .iter()
+            .map(|f| {
+                let properties = f.properties.as_ref().unwrap();
+                (
+                    properties["id"].as_i64().unwrap(),
+                    properties["change"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_added_and_removed() {
+        let old = collection(vec![feature(1, vec![0.0, 0.0], 1.0)]);
+        let new = collection(vec![feature(2, vec![0.0, 0.0], 1.0)]);
+
+        let (diff, summary) = diff_results("a", "b", old, new).unwrap();
+
+        assert_eq!((summary.added, summary.removed), (1, 1));
+        let changes = changes(diff);
+        assert_eq!(changes[&1], "removed");
+        assert_eq!(changes[&2], "added");
+    }
+
+    #[test]
+    fn test_tag_changes() {
+        let old = collection(vec![
+            feature(1, vec![0.0, 0.0], 0.5),
+            feature(2, vec![1.0, 1.0], 1.0),
+            feature(3, vec![2.0, 2.0], 0.5),
+        ]);
+        let new = collection(vec![
+            feature(1, vec![0.0, 0.0], 1.0),
+            feature(2, vec![1.0, 1.0], 0.5),
+            feature(3, vec![2.0, 2.0], 0.75),
+        ]);
+
+        let (diff, summary) = diff_results("a", "b", old, new).unwrap();
+
+        assert_eq!(summary.completed, 1);
+        assert_eq!(summary.regressed, 1);
+        assert_eq!(summary.modified, 1);
+        assert_eq!((summary.added, summary.removed), (0, 0));
+        let changes = changes(diff);
+        assert_eq!(changes[&1], "completed");
+        assert_eq!(changes[&2], "regressed");
+        assert_eq!(changes[&3], "modified");
+    }
+
+    #[test]
+    fn test_geometry_changes() {
+        let old = collection(vec![
+            feature(1, vec![0.0, 0.0], 1.0),
+            feature(2, vec![1.0, 1.0], 1.0),
+        ]);
+        let new = collection(vec![
+            feature(1, vec![0.5, 0.5], 1.0),
+            feature(2, vec![1.0, 1.0], 1.0),
+        ]);
+
+        let (diff, summary) = diff_results("a", "b", old, new).unwrap();
+
+        assert_eq!(summary.modified, 1);
+        let changes = changes(diff);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[&1], "modified");
+    }
+
+    #[test]
+    fn test_nodes_and_ways_with_the_same_id() {
+        let mut way = feature(1, vec![0.0, 0.0], 1.0);
+        way.geometry = Some(Geometry::new(Value::LineString(vec![
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+        ])));
+        let old = collection(vec![feature(1, vec![0.0, 0.0], 1.0)]);
+        let new = collection(vec![feature(1, vec![0.0, 0.0], 1.0), way]);
+
+        let (_diff, summary) = diff_results("a", "b", old, new).unwrap();
+
+        assert_eq!(
+            (summary.added, summary.removed, summary.modified),
+            (1, 0, 0)
+        );
+    }
+}
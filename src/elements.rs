@@ -1,8 +1,10 @@
 use crate::campaign::SearchTag;
+use crate::errors::AppError;
 use crate::parser::create_key;
 use geojson::{Feature, Geometry, Value};
 use serde_json::{to_value, Map};
 use std::collections::HashMap;
+use std::str::FromStr;
 use xml::attribute::OwnedAttribute;
 
 use serde::Serialize;
@@ -15,14 +17,33 @@ pub fn find_attribute(name: &str, attributes: &Vec<OwnedAttribute>) -> String {
     attr
 }
 
+/// Value of a numeric attribute, an error when it is missing or malformed.
+pub fn parse_attribute<T: FromStr>(
+    name: &str,
+    attributes: &Vec<OwnedAttribute>,
+) -> Result<T, AppError> {
+    let value = find_attribute(name, attributes);
+
+    value.parse::<T>().map_err(|_e| {
+        AppError::RunError(format!(
+            "Invalid OSM XML - {} is not a valid {}",
+            value, name
+        ))
+    })
+}
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(rename_all = "snake_case")]
-enum TagError {
+pub enum TagError {
     KeyNotFound(String),
     ValueNotFound(String),
 }
 
-fn check_value(tag_value: &String, values: &Vec<String>, key: &String) -> Result<String, TagError> {
+pub fn check_value(
+    tag_value: &String,
+    values: &[String],
+    key: &String,
+) -> Result<String, TagError> {
     let key = key.to_string();
     let values_str = format!("{}={}", key, values.join(","));
 
@@ -166,7 +187,11 @@ impl Element {
         self.coords.push(coords);
     }
 
-    pub fn set_properties(&mut self, element: &str, attributes: &Vec<OwnedAttribute>) {
+    pub fn set_properties(
+        &mut self,
+        element: &str,
+        attributes: &Vec<OwnedAttribute>,
+    ) -> Result<(), AppError> {
         let element_type = match element {
             "node" => ElementType::Node,
             "way" => ElementType::Way,
@@ -174,12 +199,8 @@ impl Element {
         };
 
         if element_type == ElementType::Node {
-            let lat = find_attribute("lat", &attributes)
-                .parse::<f64>()
-                .expect("Error parsing");
-            let lon = find_attribute("lon", &attributes)
-                .parse::<f64>()
-                .expect("Error parsing");
+            let lat = parse_attribute::<f64>("lat", attributes)?;
+            let lon = parse_attribute::<f64>("lon", attributes)?;
             let coords = vec![lon, lat];
 
             self.add_coords(coords);
@@ -187,12 +208,12 @@ impl Element {
 
         self.element_type = Some(element_type);
 
-        let id = find_attribute("id", &attributes)
-            .parse::<i64>()
-            .expect("Error parsing");
+        let id = parse_attribute::<i64>("id", attributes)?;
 
         let user = find_attribute("user", &attributes);
         self.props = Some(ElementProps { id: id, user: user });
+
+        Ok(())
     }
 
     fn create_point(&self, geometry_types: &Vec<String>) -> Option<Geometry> {
@@ -205,7 +226,7 @@ impl Element {
     }
 
     fn create_linestring(&self, geometry_types: &Vec<String>) -> Option<Geometry> {
-        if geometry_types.contains(&"linestrings".to_string()) == false {
+        if geometry_types.contains(&"lines".to_string()) == false {
             return None;
         }
         let geom = Geometry::new(Value::LineString(self.coords.clone()));
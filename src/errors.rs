@@ -3,12 +3,25 @@ use std::io;
 
 use log::error;
 
+use crate::migrations::SCHEMA_VERSION;
+use crate::validation::FieldError;
+
 #[derive(Debug)]
 pub enum AppError {
     NotFound,
     IOError(String),
     SerdeError(String),
     RunError(String),
+    DatabaseError(String),
+    /// The campaign was written since the given version, holds the current one.
+    StaleVersion(u64),
+    /// The operation clashes with the current state, e.g. an existing uuid.
+    Conflict(String),
+    ValidationError(Vec<FieldError>),
+    /// Settings from mc2.toml or the environment that can't be used.
+    ConfigError(String),
+    /// The stored campaign was written by a newer mc2, holds its schema version.
+    NewerSchema(u64),
 }
 
 impl From<serde_json::Error> for AppError {
@@ -17,6 +30,15 @@ impl From<serde_json::Error> for AppError {
     }
 }
 
+impl From<rusqlite::Error> for AppError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error {
+            rusqlite::Error::QueryReturnedNoRows => AppError::NotFound,
+            _ => AppError::DatabaseError(error.to_string()),
+        }
+    }
+}
+
 impl From<io::Error> for AppError {
     fn from(error: io::Error) -> Self {
         error!("{:?}", error.to_string());
@@ -29,6 +51,31 @@ impl From<io::Error> for AppError {
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "An error ocurred!")
+        match self {
+            AppError::ValidationError(errors) => {
+                let errors = errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect::<Vec<String>>();
+                write!(f, "Invalid campaign\n{}", errors.join("\n"))
+            }
+            AppError::NotFound => write!(f, "Not found"),
+            AppError::StaleVersion(version) => {
+                write!(f, "Campaign was modified, current version is {}", version)
+            }
+            AppError::NewerSchema(version) => write!(
+                f,
+                "Campaign has schema version {}, newer than {}",
+                version, SCHEMA_VERSION
+            ),
+            AppError::IOError(msg)
+            | AppError::SerdeError(msg)
+            | AppError::RunError(msg)
+            | AppError::DatabaseError(msg)
+            | AppError::Conflict(msg)
+            | AppError::ConfigError(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
     }
 }
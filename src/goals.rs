@@ -0,0 +1,241 @@
+use crate::campaign::SearchTag;
+use crate::parser::{create_key, Stats};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// An absolute number of features, or a share of the features found.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    Count(i64),
+    Percentage(f64),
+}
+
+/// A target on a search tag, or on one of its secondary tags, e.g. 2000
+/// `building` features with `building:material`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Goal {
+    pub tag: String,
+    pub secondary: Option<String>,
+    pub target: Target,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GoalProgress {
+    #[serde(flatten)]
+    pub goal: Goal,
+    /// Features counted towards the goal, or their percentage.
+    pub current: f64,
+    /// Share of the target reached, from 0 to 1.
+    pub progress: f64,
+    pub achieved: bool,
+}
+
+impl Goal {
+    /// Features found for the search tag, and how many of them count towards
+    /// the goal: those with the secondary tag, or complete ones without it.
+    fn counts(&self, tags: &HashMap<String, SearchTag>, stats: &Stats) -> Option<(i64, i64)> {
+        let search_tag = tags.get(&self.tag)?;
+        let key = create_key(&self.tag, &search_tag.values);
+        let total = stats.feature_counts.get(&key).copied().unwrap_or(0);
+
+        let matched = match &self.secondary {
+            None => stats
+                .completeness_count
+                .get(&key)
+                .and_then(|c| c.get("complete")),
+            Some(secondary) => {
+                let values = &search_tag.secondary.as_ref()?.get(secondary)?.values;
+                stats
+                    .attributes_count
+                    .get(&key)
+                    .and_then(|a| a.get(&create_key(secondary, values)))
+            }
+        };
+
+        Some((total, matched.copied().unwrap_or(0)))
+    }
+
+    pub fn progress(
+        &self,
+        tags: &HashMap<String, SearchTag>,
+        stats: &Stats,
+    ) -> Option<GoalProgress> {
+        let (total, matched) = self.counts(tags, stats)?;
+
+        let (current, target) = match (self.target, &self.secondary) {
+            (Target::Count(n), None) => (total as f64, n as f64),
+            (Target::Count(n), Some(_)) => (matched as f64, n as f64),
+            (Target::Percentage(p), _) => match total {
+                0 => (0.0, p),
+                _ => (matched as f64 * 100.0 / total as f64, p),
+            },
+        };
+
+        // Validation rejects empty targets, but stored goals may predate it.
+        let progress = match target {
+            t if t > 0.0 => (current / t).min(1.0),
+            _ => 1.0,
+        };
+
+        Some(GoalProgress {
+            goal: self.clone(),
+            current,
+            progress,
+            achieved: current >= target,
+        })
+    }
+}
+
+/// Progress of every goal whose tags are part of the campaign.
+pub fn goal_progress(
+    goals: &[Goal],
+    tags: &HashMap<String, SearchTag>,
+    stats: &Stats,
+) -> Vec<GoalProgress> {
+    goals
+        .iter()
+        .filter_map(|g| g.progress(tags, stats))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> HashMap<String, SearchTag> {
+        let mut secondary = HashMap::new();
+        secondary.insert(
+            "building:material".to_string(),
+            SearchTag {
+                values: Vec::new(),
+                secondary: None,
+            },
+        );
+
+        let mut tags = HashMap::new();
+        tags.insert(
+            "building".to_string(),
+            SearchTag {
+                values: vec!["yes".to_string()],
+                secondary: Some(secondary),
+            },
+        );
+        tags
+    }
+
+    /// Results with `total` buildings, `complete` of them with a material.
+    fn stats(total: i64, complete: i64) -> Stats {
+        let key = "building=yes".to_string();
+        let mut stats = Stats::default();
+        stats.feature_counts.insert(key.clone(), total);
+
+        let mut attributes = HashMap::new();
+        attributes.insert("building:material".to_string(), complete);
+        stats.attributes_count.insert(key.clone(), attributes);
+
+        let mut completeness = HashMap::new();
+        completeness.insert("complete".to_string(), complete);
+        completeness.insert("incomplete".to_string(), total - complete);
+        stats.completeness_count.insert(key, completeness);
+
+        stats
+    }
+
+    fn goal(secondary: Option<&str>, target: Target) -> Goal {
+        Goal {
+            tag: "building".to_string(),
+            secondary: secondary.map(|s| s.to_string()),
+            target,
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = goal(None, Target::Count(40))
+            .progress(&tags(), &stats(10, 4))
+            .unwrap();
+        assert_eq!(progress.current, 10.0);
+        assert_eq!(progress.progress, 0.25);
+        assert!(!progress.achieved);
+
+        let progress = goal(Some("building:material"), Target::Count(8))
+            .progress(&tags(), &stats(10, 4))
+            .unwrap();
+        assert_eq!(progress.current, 4.0);
+        assert_eq!(progress.progress, 0.5);
+
+        let progress = goal(None, Target::Percentage(80.0))
+            .progress(&tags(), &stats(10, 4))
+            .unwrap();
+        assert_eq!(progress.current, 40.0);
+        assert_eq!(progress.progress, 0.5);
+    }
+
+    #[test]
+    fn test_zero_targets() {
+        for target in &[Target::Count(0), Target::Percentage(0.0)] {
+            let progress = goal(None, *target)
+                .progress(&tags(), &stats(10, 4))
+                .unwrap();
+            assert_eq!(progress.progress, 1.0);
+            assert!(progress.achieved);
+
+            let progress = goal(None, *target)
+                .progress(&tags(), &Stats::default())
+                .unwrap();
+            assert_eq!(progress.current, 0.0);
+            assert_eq!(progress.progress, 1.0);
+            assert!(progress.achieved);
+        }
+    }
+
+    #[test]
+    fn test_overshoot() {
+        let progress = goal(None, Target::Count(5))
+            .progress(&tags(), &stats(12, 4))
+            .unwrap();
+        assert_eq!(progress.current, 12.0);
+        assert_eq!(progress.progress, 1.0);
+        assert!(progress.achieved);
+
+        let progress = goal(None, Target::Percentage(25.0))
+            .progress(&tags(), &stats(10, 10))
+            .unwrap();
+        assert_eq!(progress.current, 100.0);
+        assert_eq!(progress.progress, 1.0);
+        assert!(progress.achieved);
+    }
+
+    #[test]
+    fn test_missing_results() {
+        for target in &[Target::Count(10), Target::Percentage(50.0)] {
+            for secondary in &[None, Some("building:material")] {
+                let progress = goal(*secondary, *target)
+                    .progress(&tags(), &Stats::default())
+                    .unwrap();
+                assert_eq!(progress.current, 0.0);
+                assert_eq!(progress.progress, 0.0);
+                assert!(!progress.achieved);
+            }
+        }
+    }
+
+    #[test]
+    fn test_goals_on_other_tags_are_left_out() {
+        let goals = vec![
+            goal(None, Target::Count(10)),
+            Goal {
+                tag: "highway".to_string(),
+                ..goal(None, Target::Count(10))
+            },
+            goal(Some("name"), Target::Count(10)),
+        ];
+
+        let progress = goal_progress(&goals, &tags(), &stats(10, 4));
+
+        assert_eq!(progress.len(), 1);
+        assert_eq!(progress[0].goal.tag, "building");
+    }
+}
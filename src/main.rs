@@ -1,26 +1,47 @@
+mod archive;
+mod cache;
 mod campaign;
 mod commands;
+mod config;
+mod diff;
 mod elements;
 mod errors;
+mod goals;
+mod migrations;
 mod notifications;
 mod overpass;
 mod parser;
+mod retention;
+mod scheduler;
 mod server;
+mod source;
 mod storage;
+mod tasks;
+mod templates;
+mod tm;
+mod validation;
 
+use archive::OnCollision;
+use cache::OverpassCache;
 use campaign::Campaign;
-use commands::{create_campaign, load_campaign, CommandResult};
+use commands::{
+    create_campaign, create_from_template, diff_campaign, export_campaign, gc, import_campaign,
+    import_tm, load_campaign, migrate, query_campaign, scheduler, token, CommandResult,
+};
+use config::Config;
 use log::{error, info};
 use notifications::Notifications;
-use server::serve;
+use server::{serve, ServeOptions};
 
 use serde_json;
 
 use std::path::PathBuf;
-use storage::LocalStorage;
+use storage::Backend;
 use structopt::StructOpt;
+use templates::Templates;
 
 use parser::parse;
+use retention::RetentionPolicy;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "mc2", about = "Command line for MapCampaigner v2")]
@@ -29,20 +50,67 @@ struct Opts {
     /// The command to run
     command: Command,
 
-    // Debug mode, does not delete temp folder.
+    /// Debug mode, keeps the raw Overpass response of each run.
     #[structopt(short, long)]
     debug: bool,
 
-    /// Storage folder.
+    /// Seconds an Overpass response stays valid in the cache.
+    #[structopt(long, default_value = "86400")]
+    cache_ttl: u64,
+
+    /// Where campaigns are kept, `file`, `sqlite` or `s3`.
+    #[structopt(long, default_value = "file", possible_values = &["file", "sqlite", "s3"])]
+    backend: Backend,
+
+    /// Folder with extra campaign templates, one JSON file each.
+    #[structopt(long, parse(from_os_str))]
+    templates: Option<PathBuf>,
+
+    /// Configuration file, `mc2.toml` in the working directory by default.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Storage folder, `storage` in the configuration when not given.
     #[structopt(parse(from_os_str))]
-    storage: PathBuf,
+    storage: Option<PathBuf>,
+}
+
+/// What gc removes or compresses, nothing but expired cache entries by default.
+#[derive(Debug, StructOpt)]
+struct RetentionOpts {
+    /// Delete the raw Overpass response of runs that finished parsing it.
+    #[structopt(long)]
+    drop_raw: bool,
+
+    /// Runs to keep per campaign, the latest finished one is always kept.
+    #[structopt(long)]
+    keep_runs: Option<usize>,
+
+    /// Gzip the files of runs finished more than this many days ago.
+    #[structopt(long)]
+    compress_after: Option<u64>,
+}
+
+impl RetentionOpts {
+    fn policy(&self) -> RetentionPolicy {
+        RetentionPolicy::default()
+            .set_drop_raw(self.drop_raw)
+            .set_keep_runs(self.keep_runs)
+            .set_compress_after(self.compress_after)
+    }
 }
 
 #[derive(Debug, StructOpt)]
 enum Command {
     /// Run Campaign computarion.
     #[structopt()]
-    Run { uuid: String },
+    Run {
+        uuid: String,
+
+        /// Ignore cached Overpass responses.
+        #[structopt(long)]
+        refresh: bool,
+    },
 
     /// Create storage directory.
     #[structopt()]
@@ -52,20 +120,191 @@ enum Command {
     #[structopt()]
     CreateCampaign { json_path: String },
 
+    /// Create a campaign from a template and a GeoJSON area
+    #[structopt()]
+    CreateFromTemplate {
+        template: String,
+        name: String,
+        geometry_path: String,
+    },
+
+    /// Create a campaign from a Tasking Manager project export
+    #[structopt()]
+    ImportTm { project_path: String },
+
+    /// Print the Overpass query of a campaign file or stored campaign.
+    #[structopt()]
+    Query {
+        campaign: String,
+
+        /// Indent the query by nesting level.
+        #[structopt(long)]
+        pretty: bool,
+
+        /// Run the query and save the raw response to this path.
+        #[structopt(long, parse(from_os_str))]
+        execute: Option<PathBuf>,
+    },
+
+    /// Compare the results of two runs of a campaign.
+    #[structopt()]
+    Diff {
+        uuid: String,
+        run_a: String,
+        run_b: String,
+    },
+
+    /// Re-run recurring campaigns on schedule.
     #[structopt()]
-    Serve,
+    Scheduler {
+        /// Seconds between checks for due campaigns.
+        #[structopt(long, default_value = "60")]
+        poll: u64,
+    },
+
+    /// Write a campaign, its runs and results to a .tar.gz archive.
+    #[structopt()]
+    ExportCampaign {
+        uuid: String,
+
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    /// Import a campaign archive made by export-campaign.
+    #[structopt()]
+    ImportCampaign {
+        #[structopt(parse(from_os_str))]
+        archive: PathBuf,
+
+        /// What to do when the campaign uuid is taken, `fail` or `new-uuid`.
+        #[structopt(long, default_value = "fail", possible_values = &["fail", "new-uuid"])]
+        on_collision: OnCollision,
+    },
+
+    /// Upgrade stored campaigns to the current schema version.
+    #[structopt()]
+    Migrate,
+
+    /// Apply the retention policy to stored runs and prune the Overpass cache.
+    #[structopt()]
+    Gc {
+        #[structopt(flatten)]
+        retention: RetentionOpts,
+    },
+
+    /// Print an API token for a user, signed with the secret key. The server
+    /// does not issue tokens itself.
+    #[structopt()]
+    Token { name: String, id: i64 },
+
+    #[structopt()]
+    Serve {
+        /// Id of a user allowed to use the admin endpoints, can be repeated.
+        #[structopt(long = "admin")]
+        admins: Vec<i64>,
+
+        /// Seconds between garbage collections.
+        #[structopt(long, default_value = "3600")]
+        gc_interval: u64,
+
+        #[structopt(flatten)]
+        retention: RetentionOpts,
+
+        /// Allow serving with the default secret key, for local development.
+        #[structopt(long)]
+        dev: bool,
+    },
 }
 
 fn main() {
-    env_logger::init();
     let opt = Opts::from_args();
+    let config = Config::load(opt.config.as_deref());
+
+    let log_level = config.as_ref().map_or("info", |c| c.log_level.as_str());
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
 
-    let storage = LocalStorage::new(&opt.storage);
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
+    let storage_path = match opt.storage.as_ref().or(config.storage.as_ref()) {
+        Some(path) => path,
+        None => {
+            error!("No storage folder given, pass one or set storage in the configuration");
+            return;
+        }
+    };
+
+    let storage = match storage::open(opt.backend, storage_path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not open storage - {}", e);
+            return;
+        }
+    };
+    let cache = OverpassCache::new(storage.as_ref(), opt.cache_ttl);
+    let templates = Templates::load(opt.templates.as_deref());
+    let debug = opt.debug;
 
     let result = match opt.command {
         Command::CreateCampaign { ref json_path } => create_campaign(json_path, storage),
-        Command::Run { ref uuid } => load_campaign(uuid, storage),
-        Command::Serve => serve(storage),
+        Command::CreateFromTemplate {
+            ref template,
+            ref name,
+            ref geometry_path,
+        } => create_from_template(template, name, geometry_path, &templates, storage),
+        Command::ImportTm { ref project_path } => import_tm(project_path, &templates, storage),
+        Command::Run { ref uuid, refresh } => load_campaign(
+            uuid,
+            storage,
+            cache,
+            &config.overpass_url,
+            refresh,
+            opt.debug,
+        ),
+        Command::Query {
+            ref campaign,
+            pretty,
+            execute,
+        } => query_campaign(campaign, pretty, execute, storage, &config.overpass_url),
+        Command::Diff {
+            ref uuid,
+            ref run_a,
+            ref run_b,
+        } => diff_campaign(uuid, run_a, run_b, storage),
+        Command::Scheduler { poll } => {
+            scheduler(storage, cache, &config.overpass_url, poll, opt.debug)
+        }
+        Command::ExportCampaign {
+            ref uuid,
+            ref output,
+        } => export_campaign(uuid, output, storage),
+        Command::ImportCampaign {
+            ref archive,
+            on_collision,
+        } => import_campaign(archive, on_collision, storage),
+        Command::Migrate => migrate(storage),
+        Command::Gc { ref retention } => gc(storage, cache, retention.policy()),
+        Command::Token { ref name, id } => token(name, id, &config.secret_key),
+        Command::Serve {
+            admins,
+            gc_interval,
+            ref retention,
+            dev,
+        } => config.check_server(dev).and_then(|_| {
+            let options = ServeOptions {
+                admins,
+                retention: retention.policy(),
+                gc_interval,
+                debug,
+            };
+            serve(storage, cache, templates, config.clone(), options)
+        }),
         _ => Ok(CommandResult::CreateCampaign("aaa".to_string())),
     };
 
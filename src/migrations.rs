@@ -0,0 +1,172 @@
+use crate::campaign::Campaign;
+use crate::errors::AppError;
+
+use log::debug;
+use serde_json::{Map, Value};
+
+/// Upgrades a campaign document from one schema version to the next.
+struct Migration {
+    description: &'static str,
+    apply: fn(&mut Map<String, Value>),
+}
+
+/// `MIGRATIONS[n]` upgrades documents at schema version `n`. Append new ones
+/// at the end, never edit those already released.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "Null lists become empty and search tags get their values",
+        apply: fill_defaults,
+    },
+    Migration {
+        description: "The linestrings geometry type becomes lines",
+        apply: rename_linestrings,
+    },
+];
+
+pub const SCHEMA_VERSION: u64 = MIGRATIONS.len() as u64;
+
+const LIST_FIELDS: &[&str] = &[
+    "geometry_types",
+    "hashtags",
+    "categories",
+    "goals",
+    "collaborators",
+];
+
+/// Campaigns written before the schema was versioned.
+fn fill_defaults(campaign: &mut Map<String, Value>) {
+    LIST_FIELDS.iter().for_each(|field| {
+        if campaign.get(*field).is_none_or(|v| v.is_null()) {
+            campaign.insert(field.to_string(), Value::Array(Vec::new()));
+        }
+    });
+
+    if let Some(Value::Object(tags)) = campaign.get_mut("tags") {
+        tags.values_mut().for_each(fill_tag_values);
+    }
+}
+
+fn fill_tag_values(tag: &mut Value) {
+    let tag = match tag {
+        Value::Object(t) => t,
+        _ => return,
+    };
+
+    if tag.get("values").is_none_or(|v| v.is_null()) {
+        tag.insert("values".to_string(), Value::Array(Vec::new()));
+    }
+
+    if let Some(Value::Object(secondary)) = tag.get_mut("secondary") {
+        secondary.values_mut().for_each(fill_tag_values);
+    }
+}
+
+/// Results only had lines for `linestrings` while queries only fetched them
+/// for `lines`, campaigns now use `lines` for both.
+fn rename_linestrings(campaign: &mut Map<String, Value>) {
+    if let Some(Value::Array(types)) = campaign.get_mut("geometry_types") {
+        types
+            .iter_mut()
+            .filter(|t| t.as_str() == Some("linestrings"))
+            .for_each(|t| *t = Value::from("lines"));
+    }
+}
+
+/// Schema version a stored document was written with, 0 when unversioned.
+pub fn schema_version(document: &Value) -> u64 {
+    document
+        .get("schema_version")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0)
+}
+
+/// Brings a stored document up to `SCHEMA_VERSION` and reads it.
+pub fn upgrade(mut document: Value) -> Result<Campaign, AppError> {
+    let version = schema_version(&document);
+    if version > SCHEMA_VERSION {
+        return Err(AppError::NewerSchema(version));
+    }
+
+    let fields = document
+        .as_object_mut()
+        .ok_or_else(|| AppError::SerdeError("Campaign is not a JSON object".to_string()))?;
+
+    MIGRATIONS[version as usize..].iter().for_each(|m| {
+        debug!("Migrating campaign - {}", m.description);
+        (m.apply)(fields);
+    });
+    fields.insert("schema_version".to_string(), Value::from(SCHEMA_VERSION));
+
+    Ok(serde_json::from_value(document)?)
+}
+
+/// Reads a stored campaign of any schema version.
+pub fn from_str(contents: &str) -> Result<Campaign, AppError> {
+    upgrade(serde_json::from_str(contents)?)
+}
+
+/// Outcome of `mc2 migrate` over a storage.
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: Vec<String>,
+    pub current: usize,
+    /// Campaigns that could not be read, with the reason.
+    pub unreadable: Vec<(String, String)>,
+}
+
+impl MigrationReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} migrated, {} up to date, {} unreadable",
+            self.migrated.len(),
+            self.current,
+            self.unreadable.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::unversioned_campaign;
+    use serde_json::json;
+
+    #[test]
+    fn test_upgrades_unversioned_campaigns() {
+        let document = unversioned_campaign();
+        assert_eq!(schema_version(&document), 0);
+
+        let campaign = upgrade(document).unwrap();
+        assert_eq!(campaign.schema_version, SCHEMA_VERSION);
+        assert!(campaign.hashtags.is_empty());
+        assert!(campaign.categories.is_empty());
+        assert_eq!(campaign.geometry_types, ["points", "lines"]);
+
+        let tag = &campaign.tags["building"];
+        assert!(tag.values.is_empty());
+        assert!(tag.secondary.as_ref().unwrap()["levels"].values.is_empty());
+    }
+
+    #[test]
+    fn test_only_runs_newer_migrations() {
+        let mut document = unversioned_campaign();
+        document["schema_version"] = Value::from(1);
+        document["hashtags"] = json!([]);
+        document["tags"] = json!({"building": {"values": ["yes"]}});
+
+        let campaign = upgrade(document).unwrap();
+        assert_eq!(campaign.geometry_types, ["points", "lines"]);
+    }
+
+    #[test]
+    fn test_refuses_newer_schemas() {
+        let mut document = unversioned_campaign();
+        document["schema_version"] = Value::from(SCHEMA_VERSION + 1);
+
+        assert!(matches!(
+            upgrade(document),
+            Err(AppError::NewerSchema(v)) if v == SCHEMA_VERSION + 1
+        ));
+        assert!(matches!(from_str("[]"), Err(AppError::SerdeError(_))));
+    }
+}
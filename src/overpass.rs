@@ -1,11 +1,67 @@
 use geojson::{GeoJson, Value};
+use log::debug;
 use reqwest::blocking::Client;
 use reqwest::header::{HeaderMap, HeaderValue, USER_AGENT};
+use serde::Serialize;
 use std::fs::File;
+use std::io::{copy, Read};
 
-const OVERPASS_URL: &str = "https://overpass-api.de/api/interpreter";
+pub const OVERPASS_URL: &str = "https://overpass-api.de/api/interpreter";
+const REGEX_METACHARACTERS: &str = "\\.[]()*+?{}|^$";
 
+use crate::cache::OverpassCache;
 use crate::campaign::Campaign;
+use crate::errors::AppError;
+use crate::validation::FieldError;
+
+fn invalid_geom(message: &str) -> AppError {
+    AppError::ValidationError(vec![FieldError {
+        field: "geom".to_string(),
+        message: message.to_string(),
+    }])
+}
+
+/// Generated query and area of a campaign, for inspecting it before running.
+#[derive(Serialize, Debug)]
+pub struct QueryPreview {
+    pub query: String,
+    pub area_km2: f64,
+}
+
+impl QueryPreview {
+    pub fn new(campaign: &Campaign, pretty: bool) -> Result<Self, AppError> {
+        let query = Overpass::new(campaign.clone())?.build_query();
+
+        Ok(QueryPreview {
+            query: format_query(&query, pretty),
+            area_km2: campaign.area_km2(),
+        })
+    }
+}
+
+/// Collapses the query into a single line, or re-indents it by nesting level.
+pub fn format_query(query: &str, pretty: bool) -> String {
+    let lines = query.lines().map(str::trim).filter(|l| !l.is_empty());
+
+    if !pretty {
+        return lines.collect::<Vec<&str>>().join(" ");
+    }
+
+    let mut depth: usize = 0;
+    lines
+        .map(|line| {
+            if line.starts_with(')') {
+                depth = depth.saturating_sub(1);
+            }
+            let indented = format!("{}{}", "  ".repeat(depth), line);
+            if line.ends_with('(') {
+                depth += 1;
+            }
+            indented
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
 
 #[derive(Debug)]
 pub struct Overpass {
@@ -14,21 +70,64 @@ pub struct Overpass {
     relations: Vec<String>,
     polygon_strs: Vec<String>,
     url: String,
+    cache: Option<OverpassCache>,
 }
 
 impl Overpass {
-    fn create_filter(element: &str, tag: &(&str, Vec<String>), poly_str: &String) -> String {
-        if tag.1.len() == 0 {
-            return format!("{}(poly: '{}')['{}'];", element, poly_str, tag.0);
+    /// Escapes a string for use inside a single-quoted Overpass QL literal.
+    fn escape_string(s: &str) -> String {
+        s.chars()
+            .map(|c| match c {
+                '\\' => "\\\\".to_string(),
+                '\'' => "\\'".to_string(),
+                '\n' => "\\n".to_string(),
+                '\t' => "\\t".to_string(),
+                c => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// Escapes POSIX regex metacharacters so a tag value is matched literally.
+    fn escape_regex(s: &str) -> String {
+        s.chars()
+            .map(|c| match REGEX_METACHARACTERS.contains(c) {
+                true => format!("\\{}", c),
+                false => c.to_string(),
+            })
+            .collect()
+    }
+
+    /// Values are matched exactly, like `elements::check_value` does on the parser side.
+    fn create_filter(element: &str, tag: &(&str, Vec<String>), poly_str: &str) -> String {
+        let key = Overpass::escape_string(tag.0);
+        if tag.1.is_empty() {
+            return format!("{}(poly: '{}')['{}'];", element, poly_str, key);
+        }
+
+        let values = tag
+            .1
+            .iter()
+            .map(|v| Overpass::escape_regex(v))
+            .collect::<Vec<String>>()
+            .join("|");
+        let values = Overpass::escape_string(&format!("^({})$", values));
+
+        format!("{}(poly: '{}')['{}'~'{}'];", element, poly_str, key, values)
+    }
+
+    /// Rejects tags that can not be turned into a meaningful filter.
+    pub fn check_tag(key: &str, values: &[String]) -> Result<(), String> {
+        if key.trim().is_empty() {
+            return Err("Tag keys must not be empty".to_string());
+        }
+
+        match values.iter().any(|v| v.is_empty()) {
+            true => Err(format!("Tag {} has an empty value", key)),
+            false => Ok(()),
         }
-        let values = tag.1.join(" | ");
-        return format!(
-            "{}(poly: '{}')['{}'~'{}'];",
-            element, poly_str, tag.0, values
-        );
     }
 
-    fn build_query(&self) -> String {
+    pub fn build_query(&self) -> String {
         let query = format!(
             r#"(
             (
@@ -50,43 +149,45 @@ impl Overpass {
         query
     }
 
-    fn geom(geom: &GeoJson) -> Vec<String> {
+    fn geom(geom: &GeoJson) -> Result<Vec<String>, AppError> {
         let feature_collection = match &geom {
             GeoJson::FeatureCollection(f) => f,
-            _ => panic!("Geojson must be FeatureCollection"),
+            _ => return Err(invalid_geom("Geojson must be FeatureCollection")),
         };
 
-        let externals: Vec<String> = feature_collection
+        feature_collection
             .features
             .iter()
             .map(|f| {
-                let ref value = f.geometry.as_ref().expect("Geometry not found").value;
+                let value = &f
+                    .geometry
+                    .as_ref()
+                    .ok_or_else(|| invalid_geom("Geometry not found"))?
+                    .value;
 
                 let polygons_array = match value {
                     Value::Polygon(p) => p,
-                    _ => panic!("Polygon type supported only"),
+                    _ => return Err(invalid_geom("Polygon type supported only")),
                 };
 
                 // Take external polygon.
-                let items = &polygons_array[0];
-                items
-            })
-            .map(|items| {
-                let size_vec = items.len();
-                items
+                let items = polygons_array
+                    .first()
+                    .filter(|ring| !ring.is_empty())
+                    .ok_or_else(|| invalid_geom("Polygons need an outer ring"))?;
+
+                Ok(items
                     .iter()
-                    .take(size_vec - 1)
+                    .take(items.len() - 1)
                     .map(|b| format!("{} {}", b[1], b[0]))
                     .collect::<Vec<String>>()
-                    .join(" ")
+                    .join(" "))
             })
-            .collect();
-
-        externals
+            .collect()
     }
 
-    pub fn new(campaign: Campaign) -> Overpass {
-        let polygon_strs = Overpass::geom(&campaign.geom);
+    pub fn new(campaign: Campaign) -> Result<Overpass, AppError> {
+        let polygon_strs = Overpass::geom(&campaign.geom)?;
         let mut nodes = Vec::new();
         let mut ways = Vec::new();
         let mut relations = Vec::new();
@@ -127,31 +228,215 @@ impl Overpass {
                 _ => panic!("Geometry type not recognized"),
             });
 
-        Overpass {
+        Ok(Overpass {
             nodes: nodes,
             ways: ways,
             relations: relations,
             polygon_strs: polygon_strs,
             url: OVERPASS_URL.to_string(),
+            cache: None,
+        })
+    }
+
+    /// Interpreter to send the query to, the public overpass-api.de one by default.
+    pub fn set_url(self, url: &str) -> Self {
+        Overpass {
+            url: url.to_string(),
+            ..self
+        }
+    }
+
+    pub fn set_cache(self, cache: OverpassCache) -> Self {
+        Overpass {
+            cache: Some(cache),
+            ..self
         }
     }
 
-    pub fn fetch_data(&self, storage_path: &String) {
+    fn download(&self, query: &str) -> Result<reqwest::blocking::Response, AppError> {
         let mut headers = HeaderMap::new();
         headers.insert(USER_AGENT, HeaderValue::from_static("HotOSM"));
-        let query = self.build_query();
-        println!("{}", query);
 
-        let mut resp = Client::new()
+        let resp = Client::new()
             .post(&self.url)
             .headers(headers)
-            .form(&[("data", &query)])
+            .form(&[("data", query)])
             .send()
-            .expect("Error executing overpass request");
+            .map_err(|err| AppError::RunError(err.to_string()))?;
 
-        if resp.status().is_success() {
-            let mut buffer = File::create(storage_path).expect("Could not open file");
-            resp.copy_to(&mut buffer).expect("Could not copy to file");
+        match resp.status().is_success() {
+            true => Ok(resp),
+            false => Err(AppError::RunError(format!(
+                "Overpass returned {}",
+                resp.status()
+            ))),
         }
     }
+
+    /// Streams the response, from the cache when possible.
+    pub fn stream(&self, refresh: bool) -> Result<Box<dyn Read>, AppError> {
+        let query = self.build_query();
+        debug!("{}", query);
+
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return Ok(Box::new(self.download(&query)?)),
+        };
+
+        let key = OverpassCache::key(&query);
+        let cached = match refresh {
+            true => None,
+            false => cache.get(&key),
+        };
+
+        match cached {
+            Some(path) => Ok(Box::new(File::open(path)?)),
+            None => Ok(Box::new(cache.tee(&key, self.download(&query)?)?)),
+        }
+    }
+
+    pub fn fetch_data(&self, storage_path: &str, refresh: bool) -> Result<(), AppError> {
+        let mut buffer = File::create(storage_path)?;
+        copy(&mut self.stream(refresh)?, &mut buffer)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elements::check_value;
+    use regex::Regex;
+    use xml::reader::{EventReader, XmlEvent};
+
+    /// Pulls the value regex out of a filter, undoing the QL string escaping.
+    fn filter_regex(filter: &str) -> Regex {
+        let start = filter.find("~'").unwrap() + 2;
+        let end = filter.rfind("'];").unwrap();
+
+        let mut pattern = String::new();
+        let mut chars = filter[start..end].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => pattern.push(chars.next().unwrap()),
+                c => pattern.push(c),
+            }
+        }
+
+        Regex::new(&pattern).unwrap()
+    }
+
+    fn example_tags() -> Vec<(String, String)> {
+        let file = File::open("examples/overpass.xml").unwrap();
+
+        EventReader::new(file)
+            .into_iter()
+            .filter_map(|evt| match evt.unwrap() {
+                XmlEvent::StartElement {
+                    name, attributes, ..
+                } if name.local_name == "tag" => Some((
+                    crate::elements::find_attribute("k", &attributes),
+                    crate::elements::find_attribute("v", &attributes),
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn assert_same_selection(key: &str, values: Vec<String>, candidates: &[String]) {
+        let filter = Overpass::create_filter("node", &(key, values.clone()), "0 0 0 1 1 1");
+        let regex = filter_regex(&filter);
+
+        candidates.iter().for_each(|candidate| {
+            assert_eq!(
+                regex.is_match(candidate),
+                check_value(candidate, &values, &key.to_string()).is_ok(),
+                "{} selects {} differently",
+                filter,
+                candidate
+            );
+        });
+    }
+
+    #[test]
+    fn test_values_are_anchored() {
+        let filter = Overpass::create_filter(
+            "way",
+            &("building", vec!["yes".to_string(), "house".to_string()]),
+            "0 0 0 1 1 1",
+        );
+
+        assert_eq!(
+            filter,
+            "way(poly: '0 0 0 1 1 1')['building'~'^(yes|house)$'];"
+        );
+    }
+
+    #[test]
+    fn test_key_without_values() {
+        let filter = Overpass::create_filter("node", &("amenity", vec![]), "0 0 0 1 1 1");
+
+        assert_eq!(filter, "node(poly: '0 0 0 1 1 1')['amenity'];");
+    }
+
+    #[test]
+    fn test_quotes_and_metacharacters_are_escaped() {
+        let filter = Overpass::create_filter(
+            "node",
+            &("name's", vec!["O'Neil's".to_string(), "a.b".to_string()]),
+            "0 0 0 1 1 1",
+        );
+
+        assert_eq!(
+            filter,
+            r"node(poly: '0 0 0 1 1 1')['name\'s'~'^(O\'Neil\'s|a\\.b)$'];"
+        );
+    }
+
+    #[test]
+    fn test_special_values_match_like_parser() {
+        let values = vec![
+            "a.b".to_string(),
+            "it's".to_string(),
+            "x|y".to_string(),
+            "(c)".to_string(),
+            "back\\slash".to_string(),
+        ];
+        let candidates = [
+            "a.b",
+            "axb",
+            "it's",
+            "its",
+            "x|y",
+            "x",
+            "y",
+            "(c)",
+            "c",
+            "back\\slash",
+            "a.b ",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>();
+
+        assert_same_selection("name", values, &candidates);
+    }
+
+    #[test]
+    fn test_example_data_matches_like_parser() {
+        let tags = example_tags();
+        let values = vec!["yes".to_string(), "bar".to_string(), "pub".to_string()];
+
+        ["amenity", "building", "atm"].iter().for_each(|key| {
+            let candidates = tags
+                .iter()
+                .filter(|(k, _v)| k == key)
+                .map(|(_k, v)| v.clone())
+                .collect::<Vec<String>>();
+
+            assert!(!candidates.is_empty());
+            assert_same_selection(key, values.clone(), &candidates);
+        });
+    }
 }
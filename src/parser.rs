@@ -1,31 +1,24 @@
 use std::collections::HashMap;
 
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 
 use xml::reader::{EventReader, XmlEvent};
 
-use std::io::Seek;
-use std::io::SeekFrom;
-
 use crate::campaign::SearchTag;
-
-use crate::elements::{find_attribute, Element, ElementType, LatLng, Tag};
-
-fn serialize_counter(hashmap: HashMap<String, HashMap<String, i64>>) -> String {
-    hashmap
-        .iter()
-        .map(|(k, v)| format!("\"{}\": {{ {} }}", k, serialize_hashmap(v.clone())))
-        .collect::<Vec<String>>()
-        .join(",")
-}
-
-fn serialize_hashmap(hashmap: HashMap<String, i64>) -> String {
-    hashmap
-        .into_iter()
-        .map(|(k, v)| format!(" \"{}\":{}", k, v))
-        .collect::<Vec<String>>()
-        .join(",")
+use crate::errors::AppError;
+use crate::goals::{goal_progress, Goal};
+use serde::{Deserialize, Serialize};
+
+use crate::elements::{parse_attribute, Element, ElementType, LatLng, Tag};
+
+/// Counters computed over the features of a run.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Stats {
+    pub feature_counts: HashMap<String, i64>,
+    pub contributors: HashMap<String, HashMap<String, i64>>,
+    pub attributes_count: HashMap<String, HashMap<String, i64>>,
+    pub completeness_count: HashMap<String, HashMap<String, i64>>,
 }
 
 pub fn create_key(key: &String, values: &Vec<String>) -> String {
@@ -91,15 +84,33 @@ fn init_feature_count(search_tags: &HashMap<String, SearchTag>) -> HashMap<Strin
         .collect::<HashMap<String, i64>>()
 }
 
-pub fn parse(
-    read_path: &str,
+const PROGRESS_ELEMENTS: u64 = 10_000;
+
+/// Appends a feature to the collection, after a comma unless it is the first.
+fn write_feature(writer: &mut impl Write, feature: &str, written: &mut bool) -> io::Result<()> {
+    if *written {
+        writer.write_all(b",")?;
+    }
+    writer.write_all(feature.as_bytes())?;
+    *written = true;
+
+    Ok(())
+}
+
+/// Parses the OSM XML in `reader`, calling `on_progress` with the number of
+/// elements parsed so far every few thousand elements. Malformed XML fails
+/// with a `RunError`.
+pub fn parse<R: Read, F: FnMut(u64)>(
+    reader: R,
     write_path: &str,
     search_tags: &HashMap<String, SearchTag>,
     geometry_types: &Vec<String>,
-) {
-    let file = BufReader::new(File::open(read_path).expect("Could not open xml file"));
+    goals: &[Goal],
+    mut on_progress: F,
+) -> Result<Stats, AppError> {
+    let file = BufReader::new(reader);
 
-    let writer_file = File::create(write_path).unwrap();
+    let writer_file = File::create(write_path)?;
     let mut writer = BufWriter::new(writer_file);
 
     let mut ref_nodes: HashMap<i64, LatLng> = HashMap::new();
@@ -111,32 +122,32 @@ pub fn parse(
     let mut completeness_count = init_completeness_counter(search_tags);
 
     let mut element = Element::init();
+    let mut elements_parsed: u64 = 0;
 
     let mut contributors = init_contributors_count(search_tags);
 
     let mut attributes_count = init_attributes_count(search_tags);
+    let mut written = false;
 
-    writer
-        .write(r#"{"type": "FeatureCollection","features": ["#.as_bytes())
-        .unwrap();
+    writer.write_all(r#"{"type": "FeatureCollection","features": ["#.as_bytes())?;
 
     loop {
-        let evt = parser.next().expect("Parsing error!");
+        let evt = parser
+            .next()
+            .map_err(|e| AppError::RunError(format!("Invalid OSM XML - {}", e)))?;
         match evt {
             XmlEvent::StartElement {
                 name, attributes, ..
             } => match name.local_name.as_str() {
-                "node" => element.set_properties("node", &attributes),
+                "node" => element.set_properties("node", &attributes)?,
                 // If there are tags...include them in the current element.
                 "tag" => {
                     let tag = Tag::new(&attributes);
                     element.add_tag(tag);
                 }
-                "way" => element.set_properties("way", &attributes),
+                "way" => element.set_properties("way", &attributes)?,
                 "nd" => {
-                    let id = find_attribute("ref", &attributes)
-                        .parse::<i64>()
-                        .expect("Error parsing");
+                    let id = parse_attribute::<i64>("ref", &attributes)?;
 
                     ref_nodes
                         .get(&id)
@@ -155,37 +166,31 @@ pub fn parse(
                                 );
                             }
                             _ => {
-                                element
-                                    .to_feature(
-                                        &search_tags,
-                                        &mut feature_count,
-                                        geometry_types,
-                                        &mut attributes_count,
-                                        &mut completeness_count,
-                                        &mut contributors,
-                                    )
-                                    .map(|f| {
-                                        writer
-                                            .write((f.to_string() + &",".to_string()).as_bytes())
-                                            .expect("could not save element");
-                                    });
-                            }
-                        },
-                        Some(ElementType::Way) => {
-                            element
-                                .to_feature(
-                                    &search_tags,
+                                let feature = element.to_feature(
+                                    search_tags,
                                     &mut feature_count,
                                     geometry_types,
                                     &mut attributes_count,
                                     &mut completeness_count,
                                     &mut contributors,
-                                )
-                                .map(|f| {
-                                    writer
-                                        .write((f.to_string() + &",".to_string()).as_bytes())
-                                        .expect("could not save element");
-                                });
+                                );
+                                if let Some(f) = feature {
+                                    write_feature(&mut writer, &f.to_string(), &mut written)?;
+                                }
+                            }
+                        },
+                        Some(ElementType::Way) => {
+                            let feature = element.to_feature(
+                                search_tags,
+                                &mut feature_count,
+                                geometry_types,
+                                &mut attributes_count,
+                                &mut completeness_count,
+                                &mut contributors,
+                            );
+                            if let Some(f) = feature {
+                                write_feature(&mut writer, &f.to_string(), &mut written)?;
+                            }
                         }
                         _ => continue,
                     },
@@ -193,31 +198,106 @@ pub fn parse(
                     _ => continue,
                 }
                 element = Element::init();
+
+                elements_parsed += 1;
+                if elements_parsed.is_multiple_of(PROGRESS_ELEMENTS) {
+                    on_progress(elements_parsed);
+                }
             }
             XmlEvent::EndDocument => {
-                writer.seek(SeekFrom::End(0)).unwrap();
-                writer.seek(SeekFrom::Current(-1)).unwrap();
-                writer.write(b"]").unwrap();
-
-                let feature_count_str = serialize_hashmap(feature_count);
-                let attributes_count_str = serialize_counter(attributes_count);
+                writer.write_all(b"]")?;
 
-                let completeness_count_str = serialize_counter(completeness_count);
-                let contributors_str = serialize_counter(contributors);
+                let stats = Stats {
+                    feature_counts: feature_count,
+                    contributors,
+                    attributes_count,
+                    completeness_count,
+                };
 
                 let features_str = format!(
-                    r#","properties": {{ "feature_counts": {{ {} }} , "contributors": {{ {} }}, "attributes_count": {{ {} }} , "completeness_count": {{ {} }} }} }}"#,
-                    feature_count_str,
-                    contributors_str,
-                    attributes_count_str,
-                    completeness_count_str
+                    r#","properties": {},"goals": {} }}"#,
+                    serde_json::to_string(&stats)?,
+                    serde_json::to_string(&goal_progress(goals, search_tags, &stats))?
                 );
 
-                writer.write(features_str.as_bytes()).unwrap();
+                writer.write_all(features_str.as_bytes())?;
+                writer.flush()?;
 
-                break;
+                return Ok(stats);
             }
             _ => continue,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::TempDir;
+    use geojson::GeoJson;
+    use std::fs::{create_dir_all, read_to_string};
+
+    fn tags() -> HashMap<String, SearchTag> {
+        let mut tags = HashMap::new();
+        tags.insert(
+            "amenity".to_string(),
+            SearchTag {
+                values: Vec::new(),
+                secondary: None,
+            },
+        );
+        tags
+    }
+
+    fn parse_str(dir: &TempDir, xml: &str) -> Result<Stats, AppError> {
+        create_dir_all(dir.path()).unwrap();
+        let output = dir.path().join("output.json").display().to_string();
+        let geometry_types = vec!["points".to_string(), "polygons".to_string()];
+
+        parse(
+            xml.as_bytes(),
+            &output,
+            &tags(),
+            &geometry_types,
+            &[],
+            |_n| (),
+        )
+    }
+
+    #[test]
+    fn test_parse() {
+        let dir = TempDir::new("parser-parse");
+        let xml = read_to_string("examples/overpass.xml").unwrap();
+
+        let stats = parse_str(&dir, &xml).unwrap();
+
+        let found = stats.feature_counts["amenity"];
+        assert!(found > 0);
+        let output = read_to_string(dir.path().join("output.json")).unwrap();
+        match output.parse::<GeoJson>().unwrap() {
+            GeoJson::FeatureCollection(c) => assert_eq!(c.features.len() as i64, found),
+            other => panic!("Expected a feature collection, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_xml() {
+        let dir = TempDir::new("parser-malformed");
+        let documents = &[
+            // Downloads cut short.
+            r#"<osm><node id="1" lat="1.0" lon="2.0">"#,
+            "<osm><node",
+            "",
+            r#"<osm><node id="1" lat="north" lon="2.0"/></osm>"#,
+            r#"<osm><node lat="1.0" lon="2.0"/></osm>"#,
+            r#"<osm><way id="2"><nd ref="first"/></way></osm>"#,
+        ];
+
+        for xml in documents {
+            match parse_str(&dir, xml) {
+                Err(AppError::RunError(message)) => assert!(message.contains("Invalid OSM XML")),
+                other => panic!("Expected a run error for {}, got {:?}", xml, other),
+            }
+        }
+    }
+}
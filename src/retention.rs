@@ -0,0 +1,381 @@
+use crate::cache::OverpassCache;
+use crate::errors::AppError;
+use crate::storage::{Run, Storage, GZIP_SUFFIX, OUTPUT_FILE, OVERPASS_FILE, TASKS_FILE};
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration as StdDuration;
+
+/// Files of a run worth compressing once it is old.
+const COMPRESSED_FILES: &[&str] = &[OUTPUT_FILE, TASKS_FILE, OVERPASS_FILE];
+
+/// What `mc2 gc` removes or compresses. The default keeps everything, only
+/// expired Overpass cache entries go.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    drop_raw: bool,
+    keep_runs: Option<usize>,
+    compress_after: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Deletes the raw Overpass response of runs that finished parsing it.
+    pub fn set_drop_raw(mut self, drop_raw: bool) -> Self {
+        self.drop_raw = drop_raw;
+        self
+    }
+
+    /// Keeps the latest `keep_runs` runs of each campaign, plus its latest
+    /// finished one which results are served from.
+    pub fn set_keep_runs(mut self, keep_runs: Option<usize>) -> Self {
+        self.keep_runs = keep_runs;
+        self
+    }
+
+    /// Gzips the files of runs finished more than `days` ago, but the latest.
+    pub fn set_compress_after(mut self, days: Option<u64>) -> Self {
+        self.compress_after = days.map(|d| Duration::days(d as i64));
+        self
+    }
+}
+
+/// Outcome of a garbage collection.
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub runs_deleted: usize,
+    pub raw_deleted: usize,
+    pub compressed: usize,
+    pub cache_entries: usize,
+    pub bytes_reclaimed: u64,
+    /// Campaigns that could not be collected, with the reason.
+    pub failed: Vec<(String, String)>,
+}
+
+impl GcReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{} reclaimed, {} runs deleted, {} raw responses deleted, {} files compressed, {} cache entries expired, {} failed",
+            human_bytes(self.bytes_reclaimed),
+            self.runs_deleted,
+            self.raw_deleted,
+            self.compressed,
+            self.cache_entries,
+            self.failed.len()
+        )
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Applies `policy` to every campaign and prunes the Overpass cache. A
+/// campaign that fails is reported and the others still collected.
+pub fn collect_garbage(
+    storage: &dyn Storage,
+    cache: &OverpassCache,
+    policy: &RetentionPolicy,
+) -> Result<GcReport, AppError> {
+    let mut report = GcReport::default();
+    let now = Utc::now();
+
+    for uuid in storage.campaign_uuids()? {
+        if let Err(e) = collect_campaign(storage, &uuid, policy, now, &mut report) {
+            report.failed.push((uuid, e.to_string()));
+        }
+    }
+
+    let (entries, bytes) = cache.prune()?;
+    report.cache_entries += entries;
+    report.bytes_reclaimed += bytes;
+
+    Ok(report)
+}
+
+fn collect_campaign(
+    storage: &dyn Storage,
+    uuid: &str,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+    report: &mut GcReport,
+) -> Result<(), AppError> {
+    let runs = storage.list_runs(uuid)?;
+    let latest_finished = runs.iter().rev().find(|r| r.succeeded());
+    let latest_finished = latest_finished.map(|r| r.id.clone());
+
+    for (position, run) in runs.iter().enumerate() {
+        let newer = runs.len() - position - 1;
+        // The last run may still be going, older unfinished ones failed.
+        let in_progress = newer == 0 && run.finished_at.is_none();
+        let kept = policy.keep_runs.is_none_or(|keep| newer < keep)
+            || latest_finished.as_ref() == Some(&run.id)
+            || in_progress;
+
+        if !kept {
+            report.bytes_reclaimed += storage.delete_run(uuid, &run.id)?;
+            report.runs_deleted += 1;
+            info!("Deleted run {} of {}", run.id, uuid);
+            continue;
+        }
+
+        if run.finished_at.is_none() {
+            continue;
+        }
+
+        if policy.drop_raw {
+            drop_raw(storage, uuid, Some(&run.id), report)?;
+        }
+
+        if latest_finished.as_ref() != Some(&run.id) && is_old(run, policy, now) {
+            for name in COMPRESSED_FILES {
+                compress(storage, uuid, &run.id, name, report)?;
+            }
+        }
+    }
+
+    // Campaigns run before run history was kept have their files at the top.
+    if policy.drop_raw && !storage.is_campaign_running(uuid) {
+        drop_raw(storage, uuid, None, report)?;
+    }
+
+    Ok(())
+}
+
+fn is_old(run: &Run, policy: &RetentionPolicy, now: DateTime<Utc>) -> bool {
+    match (run.finished_at, policy.compress_after) {
+        (Some(finished_at), Some(after)) => finished_at + after <= now,
+        _ => false,
+    }
+}
+
+fn drop_raw(
+    storage: &dyn Storage,
+    uuid: &str,
+    run: Option<&str>,
+    report: &mut GcReport,
+) -> Result<(), AppError> {
+    let compressed = format!("{}{}", OVERPASS_FILE, GZIP_SUFFIX);
+
+    for name in &[OVERPASS_FILE, compressed.as_str()] {
+        match storage.delete_artifact(uuid, run, name) {
+            Ok(size) => {
+                report.bytes_reclaimed += size;
+                report.raw_deleted += 1;
+            }
+            Err(AppError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces a file of a run with its gzipped version. The original goes only
+/// once the compressed one is written, so readers always find one of them.
+fn compress(
+    storage: &dyn Storage,
+    uuid: &str,
+    run: &str,
+    name: &str,
+    report: &mut GcReport,
+) -> Result<(), AppError> {
+    let contents = match storage.read_artifact(uuid, Some(run), name) {
+        Ok(contents) => contents,
+        Err(AppError::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(&contents)?;
+    let compressed = encoder.finish()?;
+
+    storage.write_artifact(
+        uuid,
+        Some(run),
+        &format!("{}{}", name, GZIP_SUFFIX),
+        &compressed,
+    )?;
+    let size = storage.delete_artifact(uuid, Some(run), name)?;
+    report.bytes_reclaimed += size.saturating_sub(compressed.len() as u64);
+    report.compressed += 1;
+
+    Ok(())
+}
+
+/// Collects garbage every `interval` seconds forever, logging what it reclaimed.
+pub fn run_collector(
+    storage: &dyn Storage,
+    cache: &OverpassCache,
+    policy: &RetentionPolicy,
+    interval: u64,
+) -> ! {
+    info!(
+        "Garbage collector started, running every {} seconds",
+        interval
+    );
+
+    loop {
+        sleep(StdDuration::from_secs(interval));
+
+        match collect_garbage(storage, cache, policy) {
+            Ok(report) => {
+                report.failed.iter().for_each(|(uuid, err)| {
+                    error!("Could not collect campaign {} - {}", uuid, err)
+                });
+                info!("Garbage collected - {}", report.summary());
+            }
+            Err(err) => error!("Garbage collection failed - {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Status;
+    use crate::storage::testing::{example_campaign, TempDir};
+    use crate::storage::{open, Backend, SharedStorage};
+
+    /// A campaign with a run per entry, finished that many days ago, still
+    /// going when none. Returns the run ids, oldest first.
+    fn campaign(dir: &TempDir, runs: &[Option<i64>]) -> (SharedStorage, String, Vec<String>) {
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid().set_status(Status::Finished))
+            .unwrap();
+
+        let ids = runs
+            .iter()
+            .map(|days| {
+                let run = storage.create_run(&uuid, false).unwrap();
+                for name in &[OUTPUT_FILE, OVERPASS_FILE] {
+                    storage
+                        .write_artifact(&uuid, Some(&run.id), name, b"{}")
+                        .unwrap();
+                }
+                let run = Run {
+                    finished_at: days.map(|d| Utc::now() - Duration::days(d)),
+                    ..run
+                };
+                storage.save_run(&uuid, &run).unwrap();
+                run.id
+            })
+            .collect();
+
+        (storage, uuid, ids)
+    }
+
+    fn collect(storage: &SharedStorage, policy: RetentionPolicy) -> GcReport {
+        let cache = OverpassCache::new(storage.as_ref(), 0);
+        let report = collect_garbage(storage.as_ref(), &cache, &policy).unwrap();
+        assert!(report.failed.is_empty());
+        report
+    }
+
+    fn run_ids(storage: &SharedStorage, uuid: &str) -> Vec<String> {
+        let runs = storage.list_runs(uuid).unwrap();
+        runs.into_iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn test_default_policy_keeps_runs() {
+        let dir = TempDir::new("gc-default");
+        let (storage, uuid, ids) = campaign(&dir, &[Some(30), Some(20), Some(10)]);
+
+        let report = collect(&storage, RetentionPolicy::default());
+
+        assert_eq!(report.runs_deleted, 0);
+        assert_eq!(report.compressed, 0);
+        assert_eq!(run_ids(&storage, &uuid), ids);
+    }
+
+    #[test]
+    fn test_keep_runs() {
+        let dir = TempDir::new("gc-keep-runs");
+        let (storage, uuid, ids) = campaign(&dir, &[Some(4), Some(3), Some(2), Some(1)]);
+
+        let report = collect(&storage, RetentionPolicy::default().set_keep_runs(Some(2)));
+
+        assert_eq!(report.runs_deleted, 2);
+        assert!(report.bytes_reclaimed > 0);
+        assert_eq!(run_ids(&storage, &uuid), ids[2..].to_vec());
+        assert!(!storage.run_path(&uuid, &ids[0]).exists());
+    }
+
+    #[test]
+    fn test_latest_finished_run_is_kept() {
+        let dir = TempDir::new("gc-latest-finished");
+        let (storage, uuid, ids) = campaign(&dir, &[Some(3), Some(2), Some(1)]);
+        // The two newer runs failed, results still come from the first.
+        for id in &ids[1..] {
+            let run = storage.load_run(&uuid, id).unwrap();
+            let failed = run.fail("Overpass timed out".to_string());
+            storage.save_run(&uuid, &failed).unwrap();
+        }
+
+        let report = collect(&storage, RetentionPolicy::default().set_keep_runs(Some(1)));
+
+        assert_eq!(report.runs_deleted, 1);
+        assert_eq!(
+            run_ids(&storage, &uuid),
+            vec![ids[0].clone(), ids[2].clone()]
+        );
+    }
+
+    #[test]
+    fn test_runs_in_progress_are_kept() {
+        let dir = TempDir::new("gc-in-progress");
+        let (storage, uuid, ids) = campaign(&dir, &[Some(2), Some(1), None]);
+
+        let policy = RetentionPolicy::default()
+            .set_keep_runs(Some(0))
+            .set_drop_raw(true)
+            .set_compress_after(Some(0));
+        collect(&storage, policy);
+
+        assert_eq!(run_ids(&storage, &uuid), ids[1..].to_vec());
+        let running = storage.run_path(&uuid, &ids[2]);
+        assert!(running.join(OVERPASS_FILE).is_file());
+        assert!(running.join(OUTPUT_FILE).is_file());
+    }
+
+    #[test]
+    fn test_runs_are_compressed_after_max_age() {
+        let dir = TempDir::new("gc-max-age");
+        let (storage, uuid, ids) = campaign(&dir, &[Some(10), Some(2), Some(9)]);
+
+        let report = collect(
+            &storage,
+            RetentionPolicy::default().set_compress_after(Some(7)),
+        );
+
+        // Only the old run is compressed, the latest stays as it is.
+        assert_eq!(report.compressed, 2);
+        let compressed = format!("{}{}", OUTPUT_FILE, GZIP_SUFFIX);
+        let old = storage.run_path(&uuid, &ids[0]);
+        assert!(old.join(&compressed).is_file());
+        assert!(!old.join(OUTPUT_FILE).exists());
+        for id in &ids[1..] {
+            let run = storage.run_path(&uuid, id);
+            assert!(run.join(OUTPUT_FILE).is_file());
+            assert!(!run.join(&compressed).exists());
+        }
+    }
+}
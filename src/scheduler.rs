@@ -0,0 +1,147 @@
+use crate::errors::AppError;
+use crate::storage::Storage;
+
+use chrono::prelude::{DateTime, Utc};
+use log::{debug, error, info};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::sleep;
+use std::time::Duration;
+
+pub const POLL_SECONDS: u64 = 60;
+
+/// Marks the campaigns that are due as run now and returns their uuids. A
+/// campaign that can't be read is logged and skipped.
+pub fn claim_due_campaigns(storage: &dyn Storage) -> Vec<String> {
+    let now = Utc::now();
+
+    let uuids = match storage.campaign_uuids() {
+        Ok(uuids) => uuids,
+        Err(err) => {
+            error!("Could not list campaigns - {:?}", err);
+            return Vec::new();
+        }
+    };
+
+    uuids
+        .into_iter()
+        .filter(|uuid| {
+            let claimed = panic::catch_unwind(AssertUnwindSafe(|| claim(storage, uuid, now)))
+                .unwrap_or_else(|_panic| Err(AppError::RunError("Panicked".to_string())));
+
+            match claimed {
+                Ok(claimed) => claimed,
+                Err(err) => {
+                    error!("Could not schedule campaign {} - {:?}", uuid, err);
+                    false
+                }
+            }
+        })
+        .collect()
+}
+
+/// Marks a campaign as run now if it is still due. Every `serve` runs a
+/// scheduler, so the write only goes through at the version found due and
+/// a stale one means another scheduler claimed it first.
+fn claim(storage: &dyn Storage, uuid: &str, now: DateTime<Utc>) -> Result<bool, AppError> {
+    let campaign = storage.load_campaign(uuid)?;
+    if !campaign.is_due(now) {
+        return Ok(false);
+    }
+
+    let claimed = storage.modify_campaign(
+        uuid,
+        Some(campaign.version),
+        Box::new(|c| c.set_last_run(now)),
+    );
+
+    match claimed {
+        Ok(_c) => Ok(true),
+        Err(AppError::StaleVersion(_v)) => {
+            debug!("Campaign {} was claimed elsewhere", uuid);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Polls the storage forever, handing every due campaign to `dispatch`.
+pub fn run_scheduler<F: Fn(String)>(storage: &dyn Storage, poll: u64, dispatch: F) -> ! {
+    info!("Scheduler started, polling every {} seconds", poll);
+
+    loop {
+        claim_due_campaigns(storage).into_iter().for_each(|uuid| {
+            info!("Scheduled run - {}", uuid);
+            dispatch(uuid)
+        });
+
+        sleep(Duration::from_secs(poll));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::{Campaign, Status};
+    use crate::storage::testing::{example_campaign, TempDir};
+    use crate::storage::{open, Backend};
+    use geojson::{GeoJson, Geometry, Value};
+
+    #[test]
+    fn test_campaigns_are_claimed_once() {
+        let dir = TempDir::new("scheduler-claim");
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let campaign = Campaign {
+            run_interval_hours: Some(1),
+            ..example_campaign().set_uuid().set_status(Status::Finished)
+        };
+        let uuid = storage.save_campaign(campaign).unwrap();
+        let now = Utc::now();
+
+        assert!(claim(storage.as_ref(), &uuid, now).unwrap());
+        assert!(!claim(storage.as_ref(), &uuid, now).unwrap());
+        assert!(claim_due_campaigns(storage.as_ref()).is_empty());
+    }
+
+    #[test]
+    fn test_campaigns_without_polygons_are_skipped() {
+        let dir = TempDir::new("scheduler-point");
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let point = Campaign {
+            run_interval_hours: Some(1),
+            geom: GeoJson::Geometry(Geometry::new(Value::Point(vec![0.0, 0.0]))),
+            ..example_campaign().set_uuid().set_status(Status::Finished)
+        };
+        storage.save_campaign(point).unwrap();
+        let campaign = Campaign {
+            run_interval_hours: Some(1),
+            ..example_campaign().set_uuid().set_status(Status::Finished)
+        };
+        let uuid = storage.save_campaign(campaign).unwrap();
+
+        let claimed = claim_due_campaigns(storage.as_ref());
+
+        assert!(claimed.contains(&uuid));
+    }
+
+    #[test]
+    fn test_campaigns_wait_for_their_start_date() {
+        let dir = TempDir::new("scheduler-start-date");
+        let storage = open(Backend::File, dir.path()).unwrap();
+        let now = Utc::now();
+        let later = Campaign {
+            start_date: Some(now + chrono::Duration::days(1)),
+            ..example_campaign().set_uuid().set_status(Status::Created)
+        };
+        let later = storage.save_campaign(later).unwrap();
+        let started = Campaign {
+            start_date: Some(now - chrono::Duration::days(1)),
+            ..example_campaign().set_uuid().set_status(Status::Created)
+        };
+        let started = storage.save_campaign(started).unwrap();
+
+        assert!(storage.load_campaign(&later).unwrap().starts_later());
+        assert_eq!(claim_due_campaigns(storage.as_ref()), vec![started]);
+        // One-off campaigns run once.
+        assert!(claim_due_campaigns(storage.as_ref()).is_empty());
+    }
+}
@@ -1,30 +1,58 @@
-use crate::campaign::{Campaign, CampaignRun, Status, User};
+use crate::archive::{export_campaign, import_campaign, OnCollision};
+use crate::cache::OverpassCache;
+use crate::campaign::{self, Campaign, Collaborator, Role, Status, User};
 use crate::commands::CommandResult;
+use crate::config::Config;
+use crate::diff::diff_runs;
 use crate::errors::AppError;
-use crate::storage::{LocalStorage, OUTPUT_FILE};
+use crate::overpass::QueryPreview;
+use crate::retention::{run_collector, RetentionPolicy};
+use crate::scheduler::{run_scheduler, POLL_SECONDS};
+use crate::storage::{
+    CampaignQuery, ResultsLocation, SharedStorage, SortOrder, Storage, MAX_LIMIT,
+};
+use crate::tasks::{task_features, TaskDetail};
+use crate::templates::Templates;
+use crate::tm::TmProject;
+use crate::validation::FieldError;
 
+use actix_web::http::header::{
+    ETag, EntityTag, Header, HeaderValue, IfMatch, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, CONTENT_ENCODING, IF_MATCH, ORIGIN,
+    VARY,
+};
+use actix_web::http::Method;
 use actix_web::middleware::{Compress, Logger};
 use actix_web::{
-    delete, dev::BodyEncoding, dev::Payload, error::ErrorUnauthorized, get, http::ContentEncoding,
-    patch, post, web, App, Error, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder,
+    delete, dev::BodyEncoding, dev::Payload, dev::Service, dev::ServiceRequest,
+    error::BlockingError, error::ErrorUnauthorized, get, http::ContentEncoding, patch, post, web,
+    App, Error, FromRequest, HttpRequest, HttpResponse, HttpServer, Responder, Scope,
 };
 
 use base64::{decode, encode};
 use itsdangerous::{default_builder, Signer};
 
 use actix::prelude::{Actor, Addr, Handler, Message, SyncArbiter, SyncContext};
-use geojson::{GeoJson, PolygonType, Value};
 
 use log::error;
+use serde::Deserialize;
 use serde_json::{to_value, Map};
 
 use actix_files::NamedFile;
+use chrono::prelude::{DateTime, Utc};
+use geojson::GeoJson;
+use std::thread;
 
-const SECRET_KEY: &str = "pleasechangeme1234";
+const MAX_ARCHIVE_BYTES: usize = 256 * 1024 * 1024;
+const CORS_MAX_AGE_SECONDS: &str = "3600";
 
 #[derive(Clone)]
 struct McActor {
-    storage: LocalStorage,
+    storage: SharedStorage,
+    cache: OverpassCache,
+    overpass_url: String,
+    debug: bool,
 }
 
 impl Actor for McActor {
@@ -35,16 +63,25 @@ impl Actor for McActor {
 #[rtype(result = "()")]
 struct McMessage {
     uuid: String,
+    refresh: bool,
 }
 
 impl Handler<McMessage> for McActor {
     type Result = ();
 
     fn handle(&mut self, msg: McMessage, _ctx: &mut SyncContext<Self>) -> Self::Result {
-        let uuid = msg.uuid.clone();
-        let campaign = self.storage.load_campaign(&uuid).unwrap();
-        let run = CampaignRun::new(campaign, self.storage.clone());
-        run.run();
+        let run = campaign::run_campaign(
+            self.storage.clone(),
+            self.cache.clone(),
+            &self.overpass_url,
+            &msg.uuid,
+            msg.refresh,
+            self.debug,
+        );
+
+        if let Err(err) = run {
+            error!("Could not load campaign {} - {}", msg.uuid, err);
+        }
     }
 }
 
@@ -81,7 +118,12 @@ impl FromRequest for User {
             Err(e) => return Box::pin(async move { Err(e) }),
         };
 
-        let signer = default_builder(SECRET_KEY).build();
+        let secret_key = match req.app_data::<web::Data<AppState>>() {
+            Some(data) => data.secret_key.clone(),
+            None => return Box::pin(async move { Err(ErrorUnauthorized("Server error")) }),
+        };
+
+        let signer = default_builder(secret_key).build();
         let unsigned = signer
             .unsign(&user_str)
             .map_err(|_e| ErrorUnauthorized("Unsigned token"));
@@ -94,30 +136,19 @@ impl FromRequest for User {
     }
 }
 
-#[post("/token")]
-async fn create_token(user: web::Json<User>) -> HttpResponse {
-    let signer = default_builder(SECRET_KEY).build();
+/// API token of `user`. The server does not hand tokens out, they are issued
+/// by whoever holds the secret key, e.g. with `mc2 token`.
+pub fn sign_token(user: &User, secret_key: &str) -> Result<String, AppError> {
+    let signer = default_builder(secret_key.to_string()).build();
 
-    let output = serde_json::to_string(&user.into_inner())
-        .map(|json| signer.sign(json))
-        .map(|json| encode(json))
-        .map(|token| {
-            let mut json = Map::new();
-            let token_value = to_value(&token).unwrap();
-            json.insert("token".to_string(), token_value);
-            json
-        })
-        .and_then(|str_data| serde_json::to_string(&str_data));
+    Ok(encode(signer.sign(serde_json::to_string(user)?)))
+}
 
-    match output {
-        Ok(str_data) => HttpResponse::Ok()
-            .content_type("application/json")
-            .body(str_data),
-        Err(e) => {
-            error!("{}", e);
-            HttpResponse::InternalServerError().body("Server error")
-        }
-    }
+fn validation_error(errors: Vec<FieldError>) -> HttpResponse {
+    let mut response = Map::new();
+    response.insert("errors".to_string(), to_value(&errors).unwrap());
+
+    HttpResponse::BadRequest().json(response)
 }
 
 #[post("/campaign")]
@@ -126,47 +157,74 @@ async fn create_campaign(
     campaign: web::Json<Campaign>,
     data: web::Data<AppState>,
 ) -> HttpResponse {
+    save_campaign(user, campaign.into_inner(), &data).await
+}
+
+#[derive(Deserialize)]
+struct TemplateCampaign {
+    name: String,
+    geom: GeoJson,
+}
+
+#[post("/campaign/template/{template}")]
+async fn create_from_template(
+    user: User,
+    web::Path(template): web::Path<String>,
+    campaign: web::Json<TemplateCampaign>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let campaign = campaign.into_inner();
+
+    match data.templates.get(&template) {
+        Some(t) => save_campaign(user, t.to_campaign(campaign.name, campaign.geom), &data).await,
+        None => HttpResponse::NotFound().body(format!("Template {} not found", template)),
+    }
+}
+
+#[post("/campaign/import/tm")]
+async fn import_tm(
+    user: User,
+    project: web::Json<TmProject>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    match project.into_inner().into_campaign(&data.templates) {
+        Ok(campaign) => save_campaign(user, campaign, &data).await,
+        Err(AppError::ValidationError(errors)) => validation_error(errors),
+        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    }
+}
+
+#[get("/templates")]
+async fn list_templates(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(data.templates.list())
+}
+
+/// Validates and stores a new campaign, then queues its first run.
+async fn save_campaign(user: User, campaign: Campaign, data: &AppState) -> HttpResponse {
     let campaign = campaign
-        .into_inner()
         .set_created_date()
         .set_uuid()
         .set_user(user)
         .set_status(Status::Created);
 
-    let ref geom = campaign.geom;
-    let feature_collection = match geom {
-        geojson::GeoJson::FeatureCollection(f) => f,
-        _ => {
-            return HttpResponse::BadRequest()
-                .content_type("text/plain")
-                .body("Geojson must be FeatureCollection")
-        }
-    };
-
-    let geometries = feature_collection
-        .features
-        .iter()
-        .map(|f| {
-            let ref value = f.geometry.as_ref().unwrap().value;
-
-            match value {
-                Value::Polygon(p) => true,
-                _ => false,
-            }
-        })
-        .filter(|x| x == &false)
-        .collect::<Vec<bool>>();
-
-    if geometries.len() > 0 {
-        return HttpResponse::BadRequest().body("Polygon geometry supported only");
+    if let Err(errors) = campaign
+        .validate()
+        .and_then(|_| campaign.validate_remote_source())
+    {
+        return validation_error(errors);
     }
 
-    let saved = data.storage.save_campaign(campaign);
+    let starts_later = campaign.starts_later();
+    let saved = blocking(&data.storage, move |s| s.save_campaign(campaign)).await;
 
     let resp = saved
         .map(|uuid| {
-            let ref addr = data.addr;
-            addr.do_send(McMessage { uuid: uuid.clone() });
+            if !starts_later {
+                data.addr.do_send(McMessage {
+                    uuid: uuid.clone(),
+                    refresh: false,
+                });
+            }
             uuid
         })
         .and_then(|uuid| {
@@ -188,8 +246,97 @@ async fn create_campaign(
     }
 }
 
+fn etag(version: u64) -> ETag {
+    ETag(EntityTag::strong(version.to_string()))
+}
+
+/// Campaign version the client last saw, from the If-Match header.
+fn if_match(req: &HttpRequest) -> Result<Option<u64>, HttpResponse> {
+    if !req.headers().contains_key(IF_MATCH) {
+        return Ok(None);
+    }
+
+    match IfMatch::parse(req) {
+        Ok(IfMatch::Any) => Ok(None),
+        Ok(IfMatch::Items(tags)) => tags
+            .first()
+            .and_then(|t| t.tag().parse::<u64>().ok())
+            .map(Some)
+            .ok_or_else(|| HttpResponse::PreconditionFailed().body("Invalid If-Match header")),
+        Err(_e) => Err(HttpResponse::PreconditionFailed().body("Invalid If-Match header")),
+    }
+}
+
+fn require_admin(user: &User, data: &AppState) -> Result<(), HttpResponse> {
+    match data.admins.contains(&user.id) {
+        true => Ok(()),
+        false => Err(HttpResponse::Forbidden().body("Not Allowed")),
+    }
+}
+
+/// Runs storage calls on the blocking thread pool. Backends may wait on the
+/// network, as S3 does, and must not hold up the server's async threads.
+async fn blocking<T, F>(storage: &SharedStorage, call: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn Storage) -> Result<T, AppError> + Send + 'static,
+{
+    let storage = storage.clone();
+
+    web::block(move || call(storage.as_ref()))
+        .await
+        .map_err(|err| match err {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => AppError::IOError("Storage call canceled".to_string()),
+        })
+}
+
+async fn load(storage: &SharedStorage, uuid: &str) -> Result<Campaign, HttpResponse> {
+    let id = uuid.to_string();
+
+    blocking(storage, move |s| s.load_campaign(&id))
+        .await
+        .map_err(|err| match err {
+            AppError::NotFound => {
+                HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
+            }
+            _ => HttpResponse::InternalServerError().body("Error found loading the campaign"),
+        })
+}
+
+/// Loads a campaign the user holds at least `role` on.
+async fn load_with_role(
+    storage: &SharedStorage,
+    uuid: &str,
+    user: &User,
+    role: Role,
+) -> Result<Campaign, HttpResponse> {
+    load(storage, uuid)
+        .await
+        .and_then(|c| match c.has_role(user, role) {
+            true => Ok(c),
+            false => Err(HttpResponse::Forbidden().body("Not Allowed")),
+        })
+}
+
+/// Loads a campaign if it is public or the user is one of its members.
+async fn load_visible(
+    storage: &SharedStorage,
+    uuid: &str,
+    user: Option<&User>,
+) -> Result<Campaign, HttpResponse> {
+    load(storage, uuid)
+        .await
+        .and_then(|c| match (c.is_visible_to(user), user) {
+            (true, _) => Ok(c),
+            (false, None) => Err(HttpResponse::Unauthorized().body("Token not found")),
+            (false, Some(_)) => Err(HttpResponse::Forbidden().body("Not Allowed")),
+        })
+}
+
 #[patch("/campaign/{uuid}")]
 async fn update_campaign(
+    req: HttpRequest,
     user: User,
     web::Path(uuid): web::Path<String>,
     data: web::Data<AppState>,
@@ -197,26 +344,70 @@ async fn update_campaign(
 ) -> HttpResponse {
     let storage = &data.storage;
 
-    let status = storage
-        .load_campaign(&uuid)
-        .map_err(|err| match err {
-            AppError::NotFound => {
-                HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
-            }
-            _ => HttpResponse::InternalServerError().body("Error found loading the campaign"),
-        })
-        .and_then(|c| match c.is_creator(&user) {
-            true => storage
-                .update_campaign(c, campaign.into_inner())
-                .map_err(|_err| {
-                    HttpResponse::InternalServerError().body("Could not update campaign")
-                }),
-            false => Err(HttpResponse::Forbidden().body("Not Allowed")),
-        });
+    if let Err(errors) = campaign
+        .validate()
+        .and_then(|_| campaign.validate_remote_source())
+    {
+        return validation_error(errors);
+    }
 
-    match status {
-        Ok(_ok) => HttpResponse::Ok().body(""),
-        Err(e) => e,
+    let version = match if_match(&req) {
+        Ok(v) => v,
+        Err(e) => return e,
+    };
+
+    if let Err(e) = load_with_role(storage, &uuid, &user, Role::Manager).await {
+        return e;
+    }
+
+    let campaign = campaign.into_inner();
+    let updated = blocking(storage, move |s| {
+        s.update_campaign(&uuid, version, campaign)
+    })
+    .await;
+
+    match updated {
+        Ok(c) => HttpResponse::Ok().set(etag(c.version)).body(""),
+        Err(AppError::StaleVersion(current)) => HttpResponse::PreconditionFailed()
+            .set(etag(current))
+            .body(AppError::StaleVersion(current).to_string()),
+        Err(_err) => HttpResponse::InternalServerError().body("Could not update campaign"),
+    }
+}
+
+#[derive(Deserialize)]
+struct RunQuery {
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[post("/campaign/{uuid}/run")]
+async fn run_campaign(
+    user: User,
+    web::Path(uuid): web::Path<String>,
+    query: web::Query<RunQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let storage = &data.storage;
+
+    if let Err(e) = load_with_role(storage, &uuid, &user, Role::Manager).await {
+        return e;
+    }
+
+    let id = uuid.clone();
+    match blocking(storage, move |s| Ok(s.is_campaign_running(&id))).await {
+        Ok(true) => HttpResponse::Conflict().body(format!("Campaign {} is running", uuid)),
+        Ok(false) => {
+            data.addr.do_send(McMessage {
+                uuid,
+                refresh: query.refresh,
+            });
+            HttpResponse::Accepted().body("")
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
     }
 }
 
@@ -228,42 +419,178 @@ async fn delete_campaign(
 ) -> HttpResponse {
     let storage = &data.storage;
 
-    let status = storage
-        .load_campaign(&uuid)
-        .map_err(|err| match err {
-            AppError::NotFound => {
-                HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
-            }
-            _ => HttpResponse::InternalServerError().body("An error ocurred loading the campaign"),
-        })
-        .map(|c| c.is_creator(&user))
-        .and_then(|is_user| match is_user {
-            true => storage.delete_campaign(&uuid).map_err(|_err| {
-                HttpResponse::InternalServerError().body("Could not delete campaign")
-            }),
-            false => Err(HttpResponse::Forbidden().body("Not Allowed")),
-        });
+    if let Err(e) = load_with_role(storage, &uuid, &user, Role::Owner).await {
+        return e;
+    }
+
+    match blocking(storage, move |s| s.delete_campaign(&uuid)).await {
+        Ok(()) => HttpResponse::Ok().body(""),
+        Err(_err) => HttpResponse::InternalServerError().body("Could not delete campaign"),
+    }
+}
 
-    match status {
-        Ok(_ok) => HttpResponse::Ok().body(""),
+#[get("/campaign/{uuid}/collaborators")]
+async fn list_collaborators(
+    user: User,
+    web::Path(uuid): web::Path<String>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    match load_with_role(&data.storage, &uuid, &user, Role::Viewer).await {
+        Ok(c) => HttpResponse::Ok().json(c.collaborators),
         Err(e) => e,
     }
 }
 
+#[post("/campaign/{uuid}/collaborators")]
+async fn add_collaborator(
+    user: User,
+    web::Path(uuid): web::Path<String>,
+    data: web::Data<AppState>,
+    collaborator: web::Json<Collaborator>,
+) -> HttpResponse {
+    let storage = &data.storage;
+    let collaborator = collaborator.into_inner();
+
+    if collaborator.role == Role::Owner {
+        return HttpResponse::BadRequest().body("Use the owner endpoint to transfer ownership");
+    }
+
+    let status = load_with_role(storage, &uuid, &user, Role::Owner)
+        .await
+        .and_then(|c| match c.user.as_ref() == Some(&collaborator.user) {
+            true => Err(HttpResponse::BadRequest().body("The owner can not be a collaborator")),
+            false => Ok(c),
+        });
+    if let Err(e) = status {
+        return e;
+    }
+
+    let modified = blocking(storage, move |s| {
+        s.modify_campaign(
+            &uuid,
+            None,
+            Box::new(|c| c.set_collaborator(collaborator.clone())),
+        )
+    });
+
+    match modified.await {
+        Ok(_c) => HttpResponse::Ok().body(""),
+        Err(_err) => HttpResponse::InternalServerError().body("Could not update collaborators"),
+    }
+}
+
+#[delete("/campaign/{uuid}/collaborators/{user_id}")]
+async fn remove_collaborator(
+    user: User,
+    web::Path((uuid, user_id)): web::Path<(String, i64)>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let storage = &data.storage;
+
+    // Collaborators can always leave a campaign on their own.
+    let role = match user.id == user_id {
+        true => Role::Viewer,
+        false => Role::Owner,
+    };
+
+    if let Err(e) = load_with_role(storage, &uuid, &user, role).await {
+        return e;
+    }
+
+    let modified = blocking(storage, move |s| {
+        s.modify_campaign(&uuid, None, Box::new(|c| c.remove_collaborator(user_id)))
+    });
+
+    match modified.await {
+        Ok(_c) => HttpResponse::Ok().body(""),
+        Err(_err) => HttpResponse::InternalServerError().body("Could not update collaborators"),
+    }
+}
+
+#[post("/campaign/{uuid}/owner")]
+async fn transfer_ownership(
+    user: User,
+    web::Path(uuid): web::Path<String>,
+    data: web::Data<AppState>,
+    new_owner: web::Json<User>,
+) -> HttpResponse {
+    let storage = &data.storage;
+
+    if let Err(e) = load_with_role(storage, &uuid, &user, Role::Owner).await {
+        return e;
+    }
+
+    let new_owner = new_owner.into_inner();
+    let modified = blocking(storage, move |s| {
+        s.modify_campaign(
+            &uuid,
+            None,
+            Box::new(|c| c.transfer_ownership(new_owner.clone())),
+        )
+    });
+
+    match modified.await {
+        Ok(_c) => HttpResponse::Ok().body(""),
+        Err(_err) => HttpResponse::InternalServerError().body("Could not transfer ownership"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResultsQuery {
+    run: Option<String>,
+}
+
 #[get("/results/{uuid}")]
 async fn get_results(
+    user: Option<User>,
     web::Path(uuid): web::Path<String>,
+    query: web::Query<ResultsQuery>,
     data: web::Data<AppState>,
     req: HttpRequest,
 ) -> HttpResponse {
     let storage = &data.storage;
 
-    if storage.is_campaign_running(&uuid) == true {
-        return HttpResponse::Conflict().body(format!("Campaign {} is running", uuid));
+    if let Err(e) = load_visible(storage, &uuid, user.as_ref()).await {
+        return e;
     }
 
-    let path = storage.path.join(uuid).join(OUTPUT_FILE);
+    let (id, run) = (uuid.clone(), query.run.clone());
+    let located = blocking(storage, move |s| {
+        let location = s.results_location(&id, run.as_deref());
+        let running = matches!(location, Err(AppError::NotFound)) && s.is_campaign_running(&id);
+        Ok((location, running))
+    });
+    let (location, running) = match located.await {
+        Ok(located) => located,
+        Err(e) => (Err(e), false),
+    };
+
+    let (path, gzipped) = match location {
+        Ok(ResultsLocation::File(path)) => (path, false),
+        Ok(ResultsLocation::Gzip(path)) => (path, true),
+        Ok(ResultsLocation::Url(url)) => {
+            return HttpResponse::TemporaryRedirect()
+                .header("Location", url)
+                .finish()
+        }
+        Err(AppError::NotFound) if running => {
+            return HttpResponse::Conflict().body(format!("Campaign {} is running", uuid))
+        }
+        Err(AppError::NotFound) => {
+            return HttpResponse::NotFound().body(format!("Results of {} not found", uuid))
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            return HttpResponse::InternalServerError().body("");
+        }
+    };
+
     match NamedFile::open(path).respond_to(&req).await {
+        // Compress leaves responses that already have an encoding alone.
+        Ok(mut r) if gzipped => HttpResponse::Ok()
+            .content_type("application/json")
+            .header(CONTENT_ENCODING, "gzip")
+            .streaming(r.take_body()),
         Ok(mut r) => HttpResponse::Ok()
             .encoding(ContentEncoding::Br)
             .streaming(r.take_body()),
@@ -271,31 +598,257 @@ async fn get_results(
     }
 }
 
+#[get("/campaign/{uuid}/runs")]
+async fn list_runs(
+    user: Option<User>,
+    web::Path(uuid): web::Path<String>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(e) = load_visible(&data.storage, &uuid, user.as_ref()).await {
+        return e;
+    }
+
+    let id = uuid.clone();
+    match blocking(&data.storage, move |s| s.list_runs(&id)).await {
+        Ok(runs) => HttpResponse::Ok().json(runs),
+        Err(AppError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
 #[get("/campaign/{uuid}")]
 async fn get_campaign(
+    user: Option<User>,
     web::Path(uuid): web::Path<String>,
     data: web::Data<AppState>,
 ) -> HttpResponse {
-    let storage = &data.storage;
-
-    match storage.load_campaign(&uuid) {
+    match load_visible(&data.storage, &uuid, user.as_ref()).await {
         Ok(campaign) => HttpResponse::Ok()
             .content_type("application/json")
+            .set(etag(campaign.version))
             .json(campaign),
-        Err(e) => match e {
-            AppError::NotFound => {
-                HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
+        Err(e) => e,
+    }
+}
+
+#[get("/campaign/{uuid}/tasks")]
+async fn list_tasks(
+    user: Option<User>,
+    web::Path(uuid): web::Path<String>,
+    query: web::Query<ResultsQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(e) = load_visible(&data.storage, &uuid, user.as_ref()).await {
+        return e;
+    }
+
+    let (id, run) = (uuid.clone(), query.run.clone());
+    match blocking(&data.storage, move |s| s.load_tasks(&id, run.as_deref())).await {
+        Ok(tasks) => HttpResponse::Ok().json(tasks),
+        Err(AppError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Tasks of {} not found", uuid))
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+#[get("/campaign/{uuid}/tasks/{task}")]
+async fn get_task(
+    user: Option<User>,
+    web::Path((uuid, task)): web::Path<(String, String)>,
+    query: web::Query<ResultsQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let storage = &data.storage;
+
+    let campaign = match load_visible(storage, &uuid, user.as_ref()).await {
+        Ok(c) => c,
+        Err(e) => return e,
+    };
+    let task_grid = match campaign.task_grid {
+        Some(g) => g,
+        None => return HttpResponse::NotFound().body(format!("Campaign {} has no tasks", uuid)),
+    };
+
+    let (id, run, task_id) = (uuid.clone(), query.run.clone(), task.clone());
+    let geom = campaign.geom;
+    let detail = blocking(storage, move |s| {
+        s.load_tasks(&id, run.as_deref())
+            .and_then(|tasks| {
+                tasks
+                    .into_iter()
+                    .find(|t| t.id == task_id)
+                    .ok_or(AppError::NotFound)
+            })
+            .and_then(|task| {
+                let results = s.load_results(&id, run.as_deref())?;
+                let features = task_features(&task_grid, &geom, &task, results)?;
+                Ok(TaskDetail { task, features })
+            })
+    });
+
+    match detail.await {
+        Ok(detail) => HttpResponse::Ok().json(detail),
+        Err(AppError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Task {} of {} not found", task, uuid))
+        }
+        Err(e) => {
+            error!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: String,
+    to: String,
+}
+
+#[get("/campaign/{uuid}/diff")]
+async fn get_diff(
+    user: Option<User>,
+    web::Path(uuid): web::Path<String>,
+    query: web::Query<DiffQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(e) = load_visible(&data.storage, &uuid, user.as_ref()).await {
+        return e;
+    }
+
+    let (id, from, to) = (uuid.clone(), query.from.clone(), query.to.clone());
+    match blocking(&data.storage, move |s| diff_runs(s, &id, &from, &to)).await {
+        Ok((collection, _summary)) => HttpResponse::Ok().json(collection),
+        Err(AppError::NotFound) => HttpResponse::NotFound().body(format!(
+            "Runs {} and {} of {} not found",
+            query.from, query.to, uuid
+        )),
+        Err(e) => {
+            error!("{:?}", e);
+            HttpResponse::InternalServerError().body("")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct QueryOptions {
+    #[serde(default)]
+    pretty: bool,
+}
+
+#[get("/campaign/{uuid}/query")]
+async fn get_campaign_query(
+    user: Option<User>,
+    web::Path(uuid): web::Path<String>,
+    options: web::Query<QueryOptions>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    match load_visible(&data.storage, &uuid, user.as_ref()).await {
+        Ok(campaign) => query_preview(&campaign, options.pretty),
+        Err(e) => e,
+    }
+}
+
+fn query_preview(campaign: &Campaign, pretty: bool) -> HttpResponse {
+    match QueryPreview::new(campaign, pretty) {
+        Ok(preview) => HttpResponse::Ok().json(preview),
+        Err(AppError::ValidationError(errors)) => validation_error(errors),
+        Err(e) => {
+            error!("Could not build query - {}", e);
+            HttpResponse::InternalServerError().body("Could not build query")
+        }
+    }
+}
+
+#[post("/query")]
+async fn preview_query(
+    campaign: web::Json<Campaign>,
+    options: web::Query<QueryOptions>,
+) -> HttpResponse {
+    if let Err(errors) = campaign.validate() {
+        return validation_error(errors);
+    }
+
+    query_preview(&campaign, options.pretty)
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    /// min lon, min lat, max lon, max lat
+    bbox: Option<String>,
+    status: Option<Status>,
+    owner: Option<i64>,
+    name: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    updated_after: Option<DateTime<Utc>>,
+    updated_before: Option<DateTime<Utc>>,
+    sort: Option<String>,
+    page: Option<usize>,
+    limit: Option<usize>,
+}
+
+impl ListQuery {
+    fn into_query(self, user: Option<&User>) -> Result<CampaignQuery, String> {
+        let bbox = match self.bbox {
+            Some(b) => {
+                let values = b
+                    .split(',')
+                    .map(|v| v.trim().parse::<f64>())
+                    .collect::<Result<Vec<f64>, _>>()
+                    .map_err(|_e| format!("Invalid bbox {}", b))?;
+                match values.as_slice() {
+                    [min_lon, min_lat, max_lon, max_lat] => {
+                        Some((*min_lon, *min_lat, *max_lon, *max_lat))
+                    }
+                    _ => return Err(format!("Invalid bbox {}, expected 4 numbers", b)),
+                }
             }
-            _ => HttpResponse::InternalServerError().body(""),
-        },
+            None => None,
+        };
+
+        let defaults = CampaignQuery::default();
+
+        Ok(CampaignQuery {
+            viewer: user.map(|u| u.id),
+            bbox,
+            status: self.status,
+            owner: self.owner,
+            name: self.name.filter(|n| !n.trim().is_empty()),
+            created_after: self.created_after,
+            created_before: self.created_before,
+            updated_after: self.updated_after,
+            updated_before: self.updated_before,
+            sort: match self.sort {
+                Some(s) => s.parse::<SortOrder>()?,
+                None => defaults.sort,
+            },
+            page: self.page.unwrap_or(defaults.page).max(1),
+            limit: self.limit.unwrap_or(defaults.limit).clamp(1, MAX_LIMIT),
+        })
     }
 }
 
 #[get("/campaigns")]
-async fn list_campaigns(data: web::Data<AppState>) -> HttpResponse {
-    let campaigns = &data.storage.list_campaigns();
+async fn list_campaigns(
+    user: Option<User>,
+    query: web::Query<ListQuery>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    let query = match query.into_inner().into_query(user.as_ref()) {
+        Ok(q) => q,
+        Err(msg) => return HttpResponse::BadRequest().body(msg),
+    };
 
-    match campaigns {
+    match blocking(&data.storage, move |s| s.query_campaigns(&query)).await {
         Ok(c) => HttpResponse::Ok().content_type("application/json").json(c),
         Err(e) => {
             error!("{:?}", e);
@@ -304,23 +857,204 @@ async fn list_campaigns(data: web::Data<AppState>) -> HttpResponse {
     }
 }
 
+#[get("/admin/campaign/{uuid}/export")]
+async fn export_archive(
+    user: User,
+    web::Path(uuid): web::Path<String>,
+    data: web::Data<AppState>,
+) -> HttpResponse {
+    if let Err(e) = require_admin(&user, &data) {
+        return e;
+    }
+
+    let id = uuid.clone();
+    let exported = blocking(&data.storage, move |s| {
+        let mut archive = Vec::new();
+        export_campaign(s, &id, &mut archive).map(|_manifest| archive)
+    });
+
+    match exported.await {
+        Ok(archive) => HttpResponse::Ok()
+            .content_type("application/gzip")
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.tar.gz\"", uuid),
+            )
+            .body(archive),
+        Err(AppError::NotFound) => {
+            HttpResponse::NotFound().body(format!("Campaign {} not found", uuid))
+        }
+        Err(AppError::Conflict(msg)) => HttpResponse::Conflict().body(msg),
+        Err(e) => {
+            error!("Could not export campaign {} - {}", uuid, e);
+            HttpResponse::InternalServerError().body("Could not export campaign")
+        }
+    }
+}
+
+fn fail_on_collision() -> OnCollision {
+    OnCollision::Fail
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    #[serde(default = "fail_on_collision")]
+    on_collision: OnCollision,
+}
+
+#[post("/admin/campaign/import")]
+async fn import_archive(
+    user: User,
+    query: web::Query<ImportQuery>,
+    data: web::Data<AppState>,
+    body: web::Bytes,
+) -> HttpResponse {
+    if let Err(e) = require_admin(&user, &data) {
+        return e;
+    }
+
+    let on_collision = query.on_collision;
+    match blocking(&data.storage, move |s| {
+        import_campaign(s, body.as_ref(), on_collision, |campaign| {
+            campaign
+                .validate()
+                .and_then(|_| campaign.validate_remote_source())
+        })
+    })
+    .await
+    {
+        Ok(uuid) => HttpResponse::Ok()
+            .content_type("application/json")
+            .json(serde_json::json!({ "uuid": uuid })),
+        Err(AppError::Conflict(msg)) => HttpResponse::Conflict().body(msg),
+        Err(AppError::ValidationError(errors)) => validation_error(errors),
+        Err(e @ AppError::SerdeError(_)) | Err(e @ AppError::IOError(_)) => {
+            HttpResponse::BadRequest().body(e.to_string())
+        }
+        Err(e) => {
+            error!("Could not import campaign - {}", e);
+            HttpResponse::InternalServerError().body("Could not import campaign")
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
-    storage: LocalStorage,
+    storage: SharedStorage,
     addr: Addr<McActor>,
+    templates: Templates,
+    admins: Vec<i64>,
+    secret_key: String,
+}
+
+/// Server settings given on the command line rather than in `mc2.toml`.
+pub struct ServeOptions {
+    pub admins: Vec<i64>,
+    pub retention: RetentionPolicy,
+    /// Seconds between garbage collections.
+    pub gc_interval: u64,
+    pub debug: bool,
+}
+
+/// Every API route, under /api/v1.
+fn api() -> Scope {
+    web::scope("/api/v1/")
+        .service(create_campaign)
+        .service(create_from_template)
+        .service(list_templates)
+        .service(import_tm)
+        .service(get_campaign)
+        .service(get_results)
+        .service(delete_campaign)
+        .service(update_campaign)
+        .service(run_campaign)
+        .service(list_collaborators)
+        .service(add_collaborator)
+        .service(remove_collaborator)
+        .service(transfer_ownership)
+        .service(get_campaign_query)
+        .service(list_runs)
+        .service(get_diff)
+        .service(list_tasks)
+        .service(get_task)
+        .service(preview_query)
+        .service(list_campaigns)
+        .service(export_archive)
+        .service(import_archive)
+}
+
+/// The request's `Origin` when browsers may call the API from it.
+fn allowed_origin(req: &ServiceRequest, cors_origins: &[String]) -> Option<HeaderValue> {
+    let origin = req.headers().get(ORIGIN)?;
+    let allowed = cors_origins
+        .iter()
+        .any(|o| o == "*" || origin.to_str().ok() == Some(o.as_str()));
+
+    match allowed {
+        true => Some(origin.clone()),
+        false => None,
+    }
 }
 
 #[actix_web::main]
-pub async fn serve(storage: LocalStorage) -> Result<CommandResult, AppError> {
+pub async fn serve(
+    storage: SharedStorage,
+    cache: OverpassCache,
+    templates: Templates,
+    config: Config,
+    options: ServeOptions,
+) -> Result<CommandResult, AppError> {
+    let ServeOptions {
+        admins,
+        retention,
+        gc_interval,
+        debug,
+    } = options;
+
+    let collector_storage = storage.clone();
+    let collector_cache = cache.clone();
+    thread::spawn(move || {
+        run_collector(
+            collector_storage.as_ref(),
+            &collector_cache,
+            &retention,
+            gc_interval,
+        )
+    });
+
+    let mc_actor = McActor {
+        storage: storage.clone(),
+        cache,
+        overpass_url: config.overpass_url.clone(),
+        debug,
+    };
+    let addr = SyncArbiter::start(config.workers, move || mc_actor.clone());
+
+    let scheduler_storage = storage.clone();
+    let scheduler_addr = addr.clone();
+    thread::spawn(move || {
+        run_scheduler(scheduler_storage.as_ref(), POLL_SECONDS, |uuid| {
+            scheduler_addr.do_send(McMessage {
+                uuid,
+                refresh: true,
+            })
+        })
+    });
+
+    let secret_key = config.secret_key.clone();
+    let cors_origins = config.cors_origins.clone();
     let server = HttpServer::new(move || {
-        let mc_actor = McActor {
-            storage: storage.clone(),
-        };
+        let cors_origins = cors_origins.clone();
+
         App::new()
             .data(AppState {
                 storage: storage.clone(),
-                addr: SyncArbiter::start(1, move || mc_actor.clone()),
+                addr: addr.clone(),
+                templates: templates.clone(),
+                admins: admins.clone(),
+                secret_key: secret_key.clone(),
             })
+            .app_data(web::PayloadConfig::new(MAX_ARCHIVE_BYTES))
             .app_data(web::JsonConfig::default().error_handler(|err, _req| {
                 actix_web::error::InternalError::from_response(
                     "",
@@ -330,21 +1064,51 @@ pub async fn serve(storage: LocalStorage) -> Result<CommandResult, AppError> {
                 )
                 .into()
             }))
+            .wrap_fn(move |req, srv| {
+                let origin = allowed_origin(&req, &cors_origins);
+                let preflight = req.method() == Method::OPTIONS
+                    && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+
+                // Preflights are answered here, the API has no OPTIONS routes.
+                let response = match (preflight, origin.clone()) {
+                    (true, Some(origin)) => Err(req.into_response(
+                        HttpResponse::NoContent()
+                            .header(ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                            .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, POST, PATCH, DELETE")
+                            .header(
+                                ACCESS_CONTROL_ALLOW_HEADERS,
+                                "Authorization, Content-Type, If-Match",
+                            )
+                            .header(ACCESS_CONTROL_MAX_AGE, CORS_MAX_AGE_SECONDS)
+                            .header(VARY, "Origin")
+                            .finish(),
+                    )),
+                    _ => Ok(srv.call(req)),
+                };
+
+                async move {
+                    let mut res = match response {
+                        Ok(future) => future.await?,
+                        Err(preflight) => return Ok(preflight),
+                    };
+                    if let Some(origin) = origin {
+                        let headers = res.headers_mut();
+                        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                        headers.insert(
+                            ACCESS_CONTROL_EXPOSE_HEADERS,
+                            HeaderValue::from_static("ETag"),
+                        );
+                        headers.append(VARY, HeaderValue::from_static("Origin"));
+                    }
+                    Ok(res)
+                }
+            })
             .wrap(Logger::default())
             .wrap(Logger::new("%a %{User-Agent}i"))
             .wrap(Compress::default())
-            .service(
-                web::scope("/api/v1/")
-                    .service(create_campaign)
-                    .service(get_campaign)
-                    .service(get_results)
-                    .service(delete_campaign)
-                    .service(update_campaign)
-                    .service(list_campaigns)
-                    .service(create_token),
-            )
+            .service(api())
     })
-    .bind("127.0.0.1:8080");
+    .bind((config.bind.as_str(), config.port));
 
     match server {
         Ok(r) => r.run().await?,
@@ -353,3 +1117,328 @@ pub async fn serve(storage: LocalStorage) -> Result<CommandResult, AppError> {
 
     Ok(CommandResult::Serve)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::Visibility;
+    use crate::storage::testing::{example_campaign, TempDir};
+    use crate::storage::{open, Backend, OUTPUT_FILE};
+    use actix_web::http::header::{HeaderMap, ETAG};
+    use actix_web::http::StatusCode;
+    use actix_web::rt::System;
+    use actix_web::test::{self, TestRequest};
+    use serde_json::json;
+
+    const SECRET_KEY: &str = "test-secret-key";
+
+    fn owner() -> User {
+        User::new("owner", 1)
+    }
+
+    fn manager() -> User {
+        User::new("manager", 2)
+    }
+
+    fn viewer() -> User {
+        User::new("viewer", 3)
+    }
+
+    fn stranger() -> User {
+        User::new("stranger", 4)
+    }
+
+    fn storage(dir: &TempDir) -> SharedStorage {
+        open(Backend::File, dir.path()).unwrap()
+    }
+
+    /// A campaign of `owner` with a manager and a viewer.
+    fn shared_campaign(storage: &SharedStorage, visibility: Visibility) -> String {
+        let campaign = Campaign {
+            visibility,
+            ..example_campaign()
+        };
+        let campaign = campaign
+            .set_uuid()
+            .set_user(owner())
+            .set_status(Status::Finished)
+            .set_collaborator(Collaborator {
+                user: manager(),
+                role: Role::Manager,
+            })
+            .set_collaborator(Collaborator {
+                user: viewer(),
+                role: Role::Viewer,
+            });
+
+        storage.save_campaign(campaign).unwrap()
+    }
+
+    /// Sends `request` to the API, as `user` when given.
+    fn call(
+        storage: &SharedStorage,
+        user: Option<User>,
+        request: TestRequest,
+    ) -> (StatusCode, String) {
+        let (status, _headers, body) = respond(storage, user, request);
+        (status, body)
+    }
+
+    /// Like `call`, with the response headers.
+    fn respond(
+        storage: &SharedStorage,
+        user: Option<User>,
+        request: TestRequest,
+    ) -> (StatusCode, HeaderMap, String) {
+        let storage = storage.clone();
+        let request = match user {
+            Some(user) => request.header("Authorization", sign_token(&user, SECRET_KEY).unwrap()),
+            None => request,
+        };
+
+        System::new("test").block_on(async move {
+            let actor = McActor {
+                storage: storage.clone(),
+                cache: OverpassCache::new(storage.as_ref(), 0),
+                overpass_url: String::new(),
+                debug: false,
+            };
+            let state = AppState {
+                storage,
+                addr: SyncArbiter::start(1, move || actor.clone()),
+                templates: Templates::load(None),
+                admins: Vec::new(),
+                secret_key: SECRET_KEY.to_string(),
+            };
+
+            let mut app = test::init_service(App::new().data(state).service(api())).await;
+            let response = test::call_service(&mut app, request.to_request()).await;
+            let status = response.status();
+            let headers = response.headers().clone();
+            let body = test::read_body(response).await;
+
+            (status, headers, String::from_utf8_lossy(&body).to_string())
+        })
+    }
+
+    #[test]
+    fn test_roles() {
+        let dir = TempDir::new("server-roles");
+        let storage = storage(&dir);
+        let uuid = shared_campaign(&storage, Visibility::Public);
+        let path = format!("/api/v1/campaign/{}", uuid);
+
+        let update = |user: User, name: &str| {
+            let campaign = Campaign {
+                name: name.to_string(),
+                ..example_campaign()
+            };
+            call(
+                &storage,
+                Some(user),
+                TestRequest::patch().uri(&path).set_json(&campaign),
+            )
+            .0
+        };
+        assert_eq!(update(viewer(), "By the viewer"), StatusCode::FORBIDDEN);
+        assert_eq!(update(stranger(), "By a stranger"), StatusCode::FORBIDDEN);
+        assert_eq!(update(manager(), "By the manager"), StatusCode::OK);
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "By the manager");
+
+        let collaborators = format!("{}/collaborators", path);
+        let add = |user: User| {
+            let collaborator = json!({"user": stranger(), "role": "manager"});
+            call(
+                &storage,
+                Some(user),
+                TestRequest::post()
+                    .uri(&collaborators)
+                    .set_json(&collaborator),
+            )
+            .0
+        };
+        let remove = |user: User, id: i64| {
+            let uri = format!("{}/{}", collaborators, id);
+            call(&storage, Some(user), TestRequest::delete().uri(&uri)).0
+        };
+        assert_eq!(add(manager()), StatusCode::FORBIDDEN);
+        assert_eq!(remove(manager(), viewer().id), StatusCode::FORBIDDEN);
+        assert_eq!(
+            call(
+                &storage,
+                Some(manager()),
+                TestRequest::post()
+                    .uri(&format!("{}/owner", path))
+                    .set_json(&manager()),
+            )
+            .0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(storage.load_campaign(&uuid).unwrap().collaborators.len(), 2);
+
+        assert_eq!(remove(viewer(), viewer().id), StatusCode::OK);
+        assert_eq!(add(owner()), StatusCode::OK);
+        let campaign = storage.load_campaign(&uuid).unwrap();
+        assert!(campaign.has_role(&stranger(), Role::Manager));
+        assert!(!campaign.has_role(&viewer(), Role::Viewer));
+
+        assert_eq!(
+            call(&storage, None, TestRequest::delete().uri(&path)).0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(&storage, Some(manager()), TestRequest::delete().uri(&path)).0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn test_updates_check_if_match() {
+        let dir = TempDir::new("server-if-match");
+        let storage = storage(&dir);
+        let uuid = shared_campaign(&storage, Visibility::Public);
+        let path = format!("/api/v1/campaign/{}", uuid);
+        let version = storage.load_campaign(&uuid).unwrap().version;
+
+        let update = |name: &str, if_match: Option<&str>| {
+            let campaign = Campaign {
+                name: name.to_string(),
+                ..example_campaign()
+            };
+            let request = TestRequest::patch().uri(&path).set_json(&campaign);
+            let request = match if_match {
+                Some(value) => request.header(IF_MATCH, value),
+                None => request,
+            };
+            let (status, headers, _body) = respond(&storage, Some(owner()), request);
+            let etag = headers.get(ETAG).map(|v| v.to_str().unwrap().to_string());
+            (status, etag)
+        };
+        let tag = |version: u64| format!("\"{}\"", version);
+
+        assert_eq!(
+            update("Current", Some(&tag(version))),
+            (StatusCode::OK, Some(tag(version + 1)))
+        );
+        // Someone else's change went in since the client read the campaign.
+        assert_eq!(
+            update("Stale", Some(&tag(version))),
+            (StatusCode::PRECONDITION_FAILED, Some(tag(version + 1)))
+        );
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Current");
+
+        for garbled in &["version 2", "\"two\"", "W/", "\"-1\""] {
+            let (status, _etag) = update("Garbled", Some(garbled));
+            assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{}", garbled);
+        }
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Current");
+
+        assert_eq!(
+            update("Unconditional", None),
+            (StatusCode::OK, Some(tag(version + 2)))
+        );
+        assert_eq!(
+            update("Any", Some("*")),
+            (StatusCode::OK, Some(tag(version + 3)))
+        );
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Any");
+    }
+
+    #[test]
+    fn test_forged_tokens() {
+        let dir = TempDir::new("server-tokens");
+        let storage = storage(&dir);
+        let uuid = shared_campaign(&storage, Visibility::Public);
+
+        let forged = default_builder("someone-elses-key").build();
+        let forged = encode(forged.sign(serde_json::to_string(&owner()).unwrap()));
+        let request = TestRequest::delete()
+            .uri(&format!("/api/v1/campaign/{}", uuid))
+            .header("Authorization", forged);
+
+        assert_eq!(call(&storage, None, request).0, StatusCode::UNAUTHORIZED);
+        assert!(storage.load_campaign(&uuid).is_ok());
+        assert_eq!(
+            call(
+                &storage,
+                None,
+                TestRequest::post().uri("/api/v1/token").set_json(&owner())
+            )
+            .0,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn test_private_campaigns_are_hidden() {
+        let dir = TempDir::new("server-visibility");
+        let storage = storage(&dir);
+        let private = shared_campaign(&storage, Visibility::Private);
+        let public = shared_campaign(&storage, Visibility::Public);
+
+        let get = |user: Option<User>, path: String| {
+            call(&storage, user, TestRequest::get().uri(&path)).0
+        };
+        for path in &["", "/runs", "/query", "/tasks"] {
+            let path = format!("/api/v1/campaign/{}{}", private, path);
+            assert_eq!(get(None, path.clone()), StatusCode::UNAUTHORIZED);
+            assert_eq!(get(Some(stranger()), path.clone()), StatusCode::FORBIDDEN);
+        }
+        assert_eq!(
+            get(Some(stranger()), format!("/api/v1/results/{}", private)),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get(Some(viewer()), format!("/api/v1/campaign/{}", private)),
+            StatusCode::OK
+        );
+
+        let listed = |user: Option<User>| {
+            let (status, body) = call(&storage, user, TestRequest::get().uri("/api/v1/campaigns"));
+            assert_eq!(status, StatusCode::OK);
+            let page: serde_json::Value = serde_json::from_str(&body).unwrap();
+            page["campaigns"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|c| c["uuid"].as_str().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(listed(None), [public.clone()]);
+        assert_eq!(listed(Some(stranger())), [public.clone()]);
+        assert_eq!(listed(Some(viewer())).len(), 2);
+    }
+
+    #[test]
+    fn test_diff_of_unknown_runs() {
+        let dir = TempDir::new("server-diff");
+        let storage = storage(&dir);
+        let uuid = shared_campaign(&storage, Visibility::Public);
+        let run = storage.create_run(&uuid, false).unwrap();
+        storage
+            .write_artifact(
+                &uuid,
+                Some(&run.id),
+                OUTPUT_FILE,
+                b"{\"type\": \"FeatureCollection\", \"features\": []}",
+            )
+            .unwrap();
+        storage
+            .save_run(&uuid, &run.clone().finish(Default::default(), Vec::new()))
+            .unwrap();
+
+        let diff = |uuid: &str, from: &str, to: &str| {
+            let uri = format!("/api/v1/campaign/{}/diff?from={}&to={}", uuid, from, to);
+            call(&storage, None, TestRequest::get().uri(&uri)).0
+        };
+        assert_eq!(diff(&uuid, &run.id, &run.id), StatusCode::OK);
+        assert_eq!(
+            diff(&uuid, &run.id, "20200101T000000000Z"),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(diff(&uuid, "unknown", &run.id), StatusCode::NOT_FOUND);
+        assert_eq!(diff(&uuid, "..%2F..", &run.id), StatusCode::NOT_FOUND);
+        assert_eq!(diff("unknown", &run.id, &run.id), StatusCode::NOT_FOUND);
+        assert_eq!(diff(&uuid, &run.id, ""), StatusCode::NOT_FOUND);
+    }
+}
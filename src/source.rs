@@ -0,0 +1,154 @@
+use crate::cache::OverpassCache;
+use crate::campaign::Campaign;
+use crate::errors::AppError;
+use crate::overpass::Overpass;
+
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Where a campaign gets its OSM data from. Campaigns without a source use Overpass.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    Overpass,
+    File { path: PathBuf },
+    Fixtures { dir: PathBuf },
+}
+
+pub trait DataSource {
+    /// Opens the OSM XML of a run as a stream.
+    fn fetch(&self, refresh: bool) -> Result<Box<dyn Read>, AppError>;
+}
+
+impl DataSource for Overpass {
+    fn fetch(&self, refresh: bool) -> Result<Box<dyn Read>, AppError> {
+        self.stream(refresh)
+    }
+}
+
+/// An `.osm` or `.osm.xml` file already on disk.
+pub struct LocalFile {
+    path: PathBuf,
+}
+
+impl LocalFile {
+    pub fn new(path: &Path) -> Self {
+        LocalFile {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl DataSource for LocalFile {
+    fn fetch(&self, _refresh: bool) -> Result<Box<dyn Read>, AppError> {
+        let name = self.path.display().to_string();
+        if !name.ends_with(".osm") && !name.ends_with(".osm.xml") {
+            return Err(AppError::RunError(format!(
+                "{} is not an .osm or .osm.xml file",
+                name
+            )));
+        }
+
+        Ok(Box::new(File::open(&self.path)?))
+    }
+}
+
+/// A directory of saved responses, named after the query hash (as in the
+/// Overpass cache) or after the campaign uuid.
+pub struct Fixtures {
+    dir: PathBuf,
+    names: Vec<String>,
+}
+
+impl Fixtures {
+    pub fn new(dir: &Path, campaign: &Campaign) -> Result<Self, AppError> {
+        let mut names = vec![OverpassCache::key(
+            &Overpass::new(campaign.clone())?.build_query(),
+        )];
+        if let Some(ref uuid) = campaign.uuid {
+            names.push(uuid.clone());
+        }
+
+        Ok(Fixtures {
+            dir: dir.to_path_buf(),
+            names,
+        })
+    }
+}
+
+impl DataSource for Fixtures {
+    fn fetch(&self, _refresh: bool) -> Result<Box<dyn Read>, AppError> {
+        let path = self
+            .names
+            .iter()
+            .map(|name| self.dir.join(format!("{}.osm", name)))
+            .find(|path| path.is_file())
+            .ok_or(AppError::NotFound)?;
+
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// Copies everything read from a source into a writer, used to archive raw responses.
+pub struct TeeReader<R, W> {
+    inner: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(inner: R, writer: W) -> Self {
+        TeeReader { inner, writer }
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.writer.write_all(&buf[..read])?;
+
+        Ok(read)
+    }
+}
+
+/// Counts the bytes read from a source, shared so progress can be reported while parsing.
+pub struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R, count: Rc<Cell<u64>>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+
+        Ok(read)
+    }
+}
+
+pub fn from_campaign(
+    campaign: &Campaign,
+    cache: OverpassCache,
+    overpass_url: &str,
+) -> Result<Box<dyn DataSource>, AppError> {
+    let source: Box<dyn DataSource> = match campaign.source {
+        Some(Source::File { ref path }) => Box::new(LocalFile::new(path)),
+        Some(Source::Fixtures { ref dir }) => Box::new(Fixtures::new(dir, campaign)?),
+        Some(Source::Overpass) | None => Box::new(
+            Overpass::new(campaign.clone())?
+                .set_url(overpass_url)
+                .set_cache(cache),
+        ),
+    };
+
+    Ok(source)
+}
@@ -0,0 +1,215 @@
+//! Just enough of the S3 API for the storage tests: objects with ETags,
+//! conditional PUTs and ListObjectsV2. Signatures are not checked.
+use reqwest::Url;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const BUCKET: &str = "mc2";
+
+/// Numbers the writes, so every version of an object gets its own ETag.
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+type Objects = Arc<Mutex<BTreeMap<String, (Vec<u8>, String)>>>;
+
+struct Request {
+    method: String,
+    path: String,
+    query: BTreeMap<String, String>,
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).unwrap()
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = BTreeMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let length = headers
+        .get("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (k, v) = p.split_once('=').unwrap_or((p, ""));
+            (decode(k), decode(v))
+        })
+        .collect();
+
+    Some(Request {
+        method,
+        path: decode(path),
+        query,
+        headers,
+        body,
+    })
+}
+
+fn list(objects: &BTreeMap<String, (Vec<u8>, String)>, request: &Request) -> Vec<u8> {
+    let prefix = request.query.get("prefix").cloned().unwrap_or_default();
+    let folders = request.query.get("delimiter").map(|d| d.as_str()) == Some("/");
+
+    let mut contents = String::new();
+    let mut prefixes = BTreeSet::new();
+    for (key, (body, _etag)) in objects.range(prefix.clone()..) {
+        if !key.starts_with(&prefix) {
+            break;
+        }
+
+        match key[prefix.len()..].find('/').filter(|_i| folders) {
+            Some(i) => {
+                prefixes.insert(key[..prefix.len() + i + 1].to_string());
+            }
+            None => contents.push_str(&format!(
+                "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                key,
+                body.len()
+            )),
+        }
+    }
+
+    let prefixes = prefixes
+        .iter()
+        .map(|p| format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", p))
+        .collect::<String>();
+
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><IsTruncated>false</IsTruncated>{}{}</ListBucketResult>",
+        contents, prefixes
+    )
+    .into_bytes()
+}
+
+/// Status, ETag and body of the answer to `request`.
+fn handle(objects: &Objects, request: &Request) -> (u16, Option<String>, Vec<u8>) {
+    let mut objects = objects.lock().unwrap();
+    let bucket = format!("/{}", BUCKET);
+
+    let key = match request.path.strip_prefix(&format!("{}/", bucket)) {
+        Some(key) if !key.is_empty() => key.to_string(),
+        _ if request.method == "GET" => return (200, None, list(&objects, request)),
+        _ => return (400, None, Vec::new()),
+    };
+
+    match request.method.as_str() {
+        "GET" | "HEAD" => match objects.get(&key) {
+            Some((body, etag)) => (200, Some(etag.clone()), body.clone()),
+            None => (404, None, Vec::new()),
+        },
+        "PUT" => {
+            let current = objects.get(&key).map(|(_b, etag)| etag.clone());
+            let allowed = match (
+                request.headers.get("if-match"),
+                request.headers.get("if-none-match"),
+            ) {
+                (Some(etag), _) => current.as_ref() == Some(etag),
+                (_, Some(_any)) => current.is_none(),
+                _ => true,
+            };
+            if !allowed {
+                return (412, None, Vec::new());
+            }
+
+            let etag = format!("\"{}\"", WRITES.fetch_add(1, Ordering::Relaxed));
+            objects.insert(key, (request.body.clone(), etag.clone()));
+            (200, Some(etag), Vec::new())
+        }
+        "DELETE" => {
+            objects.remove(&key);
+            (204, None, Vec::new())
+        }
+        _ => (405, None, Vec::new()),
+    }
+}
+
+fn respond(mut stream: TcpStream, request: &Request, answer: (u16, Option<String>, Vec<u8>)) {
+    let (status, etag, body) = answer;
+    let mut head = format!(
+        "HTTP/1.1 {} Fake\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        body.len()
+    );
+    if let Some(etag) = etag {
+        head.push_str(&format!("ETag: {}\r\n", etag));
+    }
+    head.push_str("\r\n");
+
+    let _ = stream.write_all(head.as_bytes());
+    if request.method != "HEAD" {
+        let _ = stream.write_all(&body);
+    }
+}
+
+/// Serves a fresh empty bucket for as long as the tests run, returns its endpoint.
+pub fn start() -> Url {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+    let objects: Objects = Arc::default();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_e) => continue,
+            };
+
+            if let Some(request) = read_request(&stream) {
+                let answer = handle(&objects, &request);
+                respond(stream, &request, answer);
+            }
+        }
+    });
+
+    endpoint
+}
@@ -0,0 +1,511 @@
+use super::query::{page_from_index, update_index, Index};
+use super::{
+    remove_folder, write_atomic, CampaignPage, CampaignQuery, CampaignSummary, Run, Storage,
+    RUNS_DIR,
+};
+use crate::campaign::Campaign;
+use crate::commands::CommandResult;
+use crate::errors::AppError;
+use crate::migrations::{self, schema_version, upgrade, MigrationReport, SCHEMA_VERSION};
+
+use fs2::FileExt;
+use log::{info, warn};
+use serde_json::{from_str, to_string, Value};
+use std::fs::{create_dir, create_dir_all};
+use std::fs::{read_to_string, remove_file, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Campaigns as JSON files, one folder per campaign.
+#[derive(Clone)]
+pub struct LocalStorage {
+    pub path: PathBuf,
+}
+
+const CAMPAIGN_FILE: &str = "campaign.json";
+const BACKUP_FILE: &str = "campaign.json.bak";
+const LOCK_FILE: &str = "campaign.lock";
+const RUN_FILE: &str = "run.json";
+const INDEX_FILE: &str = "index.json";
+const INDEX_LOCK_FILE: &str = "index.lock";
+
+/// Exclusive lock on a lock file, held by threads and processes alike until
+/// dropped.
+struct FileLock(File);
+
+impl FileLock {
+    fn acquire(path: &Path) -> Result<Self, AppError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        file.lock_exclusive()?;
+
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.unlock() {
+            warn!("Could not release lock - {}", e);
+        }
+    }
+}
+
+fn read_campaign(path: &Path) -> Result<Campaign, AppError> {
+    let contents = read_to_string(path)?;
+
+    migrations::from_str(&contents)
+}
+
+impl LocalStorage {
+    pub fn new(storage: &Path) -> Self {
+        match create_dir(storage) {
+            Ok(()) => info!(
+                "{}",
+                CommandResult::CreateStorage(storage.display().to_string()).message()
+            ),
+            Err(_e) => warn!("STORAGE {} EXISTS", storage.display().to_string()),
+        };
+
+        LocalStorage {
+            path: storage.to_path_buf(),
+        }
+    }
+
+    fn lock(&self, uuid: &str) -> Result<FileLock, AppError> {
+        let path = self.path.join(uuid);
+        if !path.is_dir() {
+            return Err(AppError::NotFound);
+        }
+
+        FileLock::acquire(&path.join(LOCK_FILE))
+    }
+
+    /// Summaries of every readable campaign, from their files.
+    fn build_index(&self) -> Result<Index, AppError> {
+        let index = self
+            .campaign_uuids()?
+            .into_iter()
+            .filter_map(|uuid| {
+                read_campaign(&self.path.join(&uuid).join(CAMPAIGN_FILE))
+                    .map_err(|e| warn!("Could not read campaign {} - {}", uuid, e))
+                    .ok()
+                    .map(|campaign| (uuid, CampaignSummary::new(&campaign)))
+            })
+            .collect();
+
+        Ok(index)
+    }
+
+    /// The stored index, none when missing or unreadable.
+    fn stored_index(&self) -> Option<Index> {
+        match read_to_string(self.path.join(INDEX_FILE)) {
+            Ok(contents) => from_str(&contents)
+                .map_err(|e| warn!("Campaign index is corrupt, rebuilding it - {}", e))
+                .ok(),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("Could not read campaign index, rebuilding it - {}", e);
+                None
+            }
+        }
+    }
+
+    fn read_index(&self) -> Result<Index, AppError> {
+        if let Some(index) = self.stored_index() {
+            return Ok(index);
+        }
+
+        let _lock = FileLock::acquire(&self.path.join(INDEX_LOCK_FILE))?;
+        let index = self.build_index()?;
+        write_atomic(&self.path.join(INDEX_FILE), to_string(&index)?.as_bytes())?;
+
+        Ok(index)
+    }
+
+    /// Refreshes the index entry of a campaign, removing it when `campaign` is
+    /// none. When that fails the index goes, so the next listing rebuilds it.
+    fn index_campaign(&self, uuid: &str, campaign: Option<&Campaign>) {
+        let path = self.path.join(INDEX_FILE);
+
+        let updated = FileLock::acquire(&self.path.join(INDEX_LOCK_FILE)).and_then(|_lock| {
+            let (mut index, built) = match self.stored_index() {
+                Some(index) => (index, false),
+                None => (self.build_index()?, true),
+            };
+
+            match update_index(&mut index, uuid, campaign) || built {
+                true => write_atomic(&path, to_string(&index)?.as_bytes()),
+                false => Ok(()),
+            }
+        });
+
+        if let Err(e) = updated {
+            warn!(
+                "Could not update the index of campaign {}, removing it - {}",
+                uuid, e
+            );
+            match remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    warn!("Could not remove the campaign index - {}", e)
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Rebuilds the index from the campaign files.
+    fn reindex(&self) -> Result<(), AppError> {
+        let _lock = FileLock::acquire(&self.path.join(INDEX_LOCK_FILE))?;
+        let index = self.build_index()?;
+
+        write_atomic(&self.path.join(INDEX_FILE), to_string(&index)?.as_bytes())
+    }
+
+    /// Writes the campaign file, keeping the version it replaces as a backup.
+    /// Callers hold the campaign lock.
+    fn write_locked(&self, campaign: &Campaign) -> Result<(), AppError> {
+        let path = self.path.join(campaign.uuid.clone().unwrap());
+        let campaign_path = path.join(CAMPAIGN_FILE);
+
+        if let Ok(previous) = read_to_string(&campaign_path) {
+            if migrations::from_str(&previous).is_ok() {
+                write_atomic(&path.join(BACKUP_FILE), previous.as_bytes())?;
+            }
+        }
+
+        write_atomic(&campaign_path, to_string(campaign)?.as_bytes())
+    }
+
+    /// Reads the campaign file, restoring the backup when it is corrupt. Files
+    /// from a newer schema are left alone.
+    /// Callers hold the campaign lock.
+    fn read_locked(&self, uuid: &str) -> Result<Campaign, AppError> {
+        let path = self.path.join(uuid);
+
+        let error = match read_campaign(&path.join(CAMPAIGN_FILE)) {
+            Err(AppError::SerdeError(error)) => error,
+            other => return other,
+        };
+
+        warn!("Campaign {} is corrupt, restoring backup - {}", uuid, error);
+        let campaign =
+            read_campaign(&path.join(BACKUP_FILE)).map_err(|_e| AppError::SerdeError(error))?;
+        write_atomic(&path.join(CAMPAIGN_FILE), to_string(&campaign)?.as_bytes())?;
+        self.index_campaign(uuid, Some(&campaign));
+
+        Ok(campaign)
+    }
+
+    /// Rewrites a campaign with the current schema, returns whether it was needed.
+    fn migrate_campaign(&self, uuid: &str) -> Result<bool, AppError> {
+        let _lock = self.lock(uuid)?;
+        let contents = read_to_string(self.path.join(uuid).join(CAMPAIGN_FILE))?;
+        let document: Value = from_str(&contents)?;
+
+        let outdated = schema_version(&document) < SCHEMA_VERSION;
+        let campaign = upgrade(document)?;
+        if outdated {
+            self.write_locked(&campaign)?;
+        }
+
+        Ok(outdated)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn root(&self) -> &Path {
+        &self.path
+    }
+
+    fn delete_campaign(&self, uuid: &str) -> Result<(), AppError> {
+        let _lock = self.lock(uuid)?;
+        let path = self.path.join(uuid);
+
+        std::fs::remove_dir_all(path)?;
+        self.index_campaign(uuid, None);
+
+        Ok(())
+    }
+
+    fn modify_campaign(
+        &self,
+        uuid: &str,
+        version: Option<u64>,
+        change: Box<dyn Fn(Campaign) -> Campaign + '_>,
+    ) -> Result<Campaign, AppError> {
+        let _lock = self.lock(uuid)?;
+
+        let campaign = self.read_locked(uuid)?;
+        campaign.check_version(version)?;
+
+        let campaign = change(campaign).next_version();
+        self.write_locked(&campaign)?;
+        self.index_campaign(uuid, Some(&campaign));
+
+        Ok(campaign)
+    }
+
+    fn load_campaign(&self, uuid: &str) -> Result<Campaign, AppError> {
+        let path = self.path.join(uuid).join(CAMPAIGN_FILE);
+
+        match read_campaign(&path) {
+            // Only restore the backup while no one else is writing.
+            Err(AppError::SerdeError(_e)) => {
+                let _lock = self.lock(uuid)?;
+                self.read_locked(uuid)
+            }
+            other => other,
+        }
+    }
+
+    fn save_campaign(&self, campaign: Campaign) -> Result<String, AppError> {
+        let uuid = campaign.uuid.clone().unwrap();
+        let path = self.path.join(uuid.clone());
+
+        create_dir(path)?;
+        let _lock = self.lock(&uuid)?;
+        self.write_locked(&campaign)?;
+        self.index_campaign(&uuid, Some(&campaign));
+
+        Ok(uuid)
+    }
+
+    /// Uuids of the folders holding a campaign file.
+    fn campaign_uuids(&self) -> Result<Vec<String>, AppError> {
+        let mut uuids = std::fs::read_dir(&self.path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join(CAMPAIGN_FILE).is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+
+        uuids.sort();
+
+        Ok(uuids)
+    }
+
+    fn list_campaigns(&self) -> Result<Vec<Campaign>, AppError> {
+        let campaigns = self
+            .campaign_uuids()?
+            .into_iter()
+            .filter_map(|uuid| {
+                read_campaign(&self.path.join(&uuid).join(CAMPAIGN_FILE))
+                    .map_err(|e| warn!("Could not read campaign {} - {}", uuid, e))
+                    .ok()
+            })
+            .map(|campaign| campaign.centroid_as_geom())
+            .collect::<Vec<Campaign>>();
+
+        Ok(campaigns)
+    }
+
+    fn query_campaigns(&self, query: &CampaignQuery) -> Result<CampaignPage, AppError> {
+        let index = self.read_index()?;
+
+        Ok(page_from_index(index, query, |uuid| {
+            self.load_campaign(uuid)
+        }))
+    }
+
+    fn migrate_campaigns(&self) -> Result<MigrationReport, AppError> {
+        let mut report = MigrationReport::default();
+
+        for uuid in self.campaign_uuids()? {
+            match self.migrate_campaign(&uuid) {
+                Ok(true) => report.migrated.push(uuid),
+                Ok(false) => report.current += 1,
+                Err(e) => report.unreadable.push((uuid, e.to_string())),
+            }
+        }
+
+        // Also picks up campaigns changed by hand.
+        self.reindex()?;
+
+        Ok(report)
+    }
+
+    fn save_run(&self, uuid: &str, run: &Run) -> Result<(), AppError> {
+        let path = self.run_path(uuid, &run.id);
+        create_dir_all(&path)?;
+
+        write_atomic(&path.join(RUN_FILE), to_string(run)?.as_bytes())
+    }
+
+    fn load_run(&self, uuid: &str, run: &str) -> Result<Run, AppError> {
+        // Run ids come from query strings, keep them inside the runs folder.
+        if !run.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(AppError::NotFound);
+        }
+
+        let contents = read_to_string(self.run_path(uuid, run).join(RUN_FILE))?;
+        let run: Run = from_str(&contents)?;
+
+        Ok(run)
+    }
+
+    fn delete_run(&self, uuid: &str, run: &str) -> Result<u64, AppError> {
+        self.load_run(uuid, run)?;
+
+        remove_folder(&self.run_path(uuid, run))
+    }
+
+    fn list_runs(&self, uuid: &str) -> Result<Vec<Run>, AppError> {
+        let path = self.path.join(uuid).join(RUNS_DIR);
+        if !path.is_dir() {
+            self.load_campaign(uuid)?;
+            return Ok(Vec::new());
+        }
+
+        let mut runs = std::fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let id = entry.file_name().to_string_lossy().to_string();
+                self.load_run(uuid, &id)
+                    .map_err(|err| warn!("Could not load run {} of {} - {:?}", id, uuid, err))
+                    .ok()
+            })
+            .collect::<Vec<Run>>();
+
+        runs.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::{example_campaign, rename, unversioned_campaign, TempDir};
+    use std::fs::write;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new("local-round-trip");
+        let storage = LocalStorage::new(dir.path());
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        let saved = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(saved.name, "Test Campaign");
+
+        let renamed = storage
+            .modify_campaign(&uuid, Some(saved.version), rename("Renamed"))
+            .unwrap();
+        assert_eq!(renamed.version, saved.version + 1);
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Renamed");
+
+        assert!(matches!(
+            storage.modify_campaign(&uuid, Some(saved.version), rename("Stale")),
+            Err(AppError::StaleVersion(v)) if v == renamed.version
+        ));
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Renamed");
+
+        storage.delete_campaign(&uuid).unwrap();
+        assert!(storage.list_campaigns().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_restores_corrupt_campaigns() {
+        let dir = TempDir::new("local-backup");
+        let storage = LocalStorage::new(dir.path());
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        let renamed = storage
+            .modify_campaign(&uuid, None, rename("Renamed"))
+            .unwrap();
+        storage
+            .modify_campaign(&uuid, None, rename("Lost"))
+            .unwrap();
+
+        let path = dir.path().join(&uuid).join(CAMPAIGN_FILE);
+        write(&path, "{\"name\": \"Lo").unwrap();
+
+        let restored = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(restored.name, "Renamed");
+        assert_eq!(restored.version, renamed.version);
+        assert_eq!(read_campaign(&path).unwrap().name, "Renamed");
+    }
+
+    #[test]
+    fn test_leaves_newer_schemas_alone() {
+        let dir = TempDir::new("local-newer-schema");
+        let storage = LocalStorage::new(dir.path());
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        storage
+            .modify_campaign(&uuid, None, rename("Renamed"))
+            .unwrap();
+
+        let path = dir.path().join(&uuid).join(CAMPAIGN_FILE);
+        let mut document: Value = from_str(&read_to_string(&path).unwrap()).unwrap();
+        document["schema_version"] = Value::from(SCHEMA_VERSION + 1);
+        let newer = to_string(&document).unwrap();
+        write(&path, &newer).unwrap();
+
+        assert!(matches!(
+            storage.load_campaign(&uuid),
+            Err(AppError::NewerSchema(_))
+        ));
+        assert_eq!(read_to_string(&path).unwrap(), newer);
+    }
+
+    #[test]
+    fn test_migrates_unversioned_campaigns() {
+        let dir = TempDir::new("local-migrate");
+        let storage = LocalStorage::new(dir.path());
+
+        let uuid = "0123456789abcdef0123456789abcdef";
+        let mut document = unversioned_campaign();
+        document["uuid"] = Value::from(uuid);
+        create_dir(dir.path().join(uuid)).unwrap();
+        write(
+            dir.path().join(uuid).join(CAMPAIGN_FILE),
+            to_string(&document).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            storage.load_campaign(uuid).unwrap().geometry_types,
+            ["points", "lines"]
+        );
+
+        let report = storage.migrate_campaigns().unwrap();
+        assert_eq!(report.migrated, [uuid]);
+        let stored = read_to_string(dir.path().join(uuid).join(CAMPAIGN_FILE)).unwrap();
+        assert_eq!(schema_version(&from_str(&stored).unwrap()), SCHEMA_VERSION);
+
+        let report = storage.migrate_campaigns().unwrap();
+        assert!(report.migrated.is_empty());
+        assert_eq!(report.current, 1);
+    }
+
+    #[test]
+    fn test_runs_started_together_get_their_own_folder() {
+        let dir = TempDir::new("local-runs");
+        let storage = LocalStorage::new(dir.path());
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        let runs = (0..5)
+            .map(|_i| storage.create_run(&uuid, false).unwrap().id)
+            .collect::<Vec<String>>();
+
+        let listed = storage.list_runs(&uuid).unwrap();
+        assert_eq!(
+            listed.iter().map(|r| &r.id).collect::<Vec<_>>(),
+            runs.iter().collect::<Vec<_>>()
+        );
+        assert!(runs
+            .iter()
+            .all(|id| id.chars().all(|c| c.is_ascii_alphanumeric())));
+    }
+}
@@ -0,0 +1,530 @@
+#[cfg(test)]
+mod fake_s3;
+mod local;
+mod query;
+mod s3;
+mod sqlite;
+
+pub use local::LocalStorage;
+pub use query::{CampaignPage, CampaignQuery, CampaignSummary, SortOrder, MAX_LIMIT};
+pub use s3::{S3Config, S3Storage};
+pub use sqlite::SqliteStorage;
+
+use crate::campaign::{Campaign, Status};
+use crate::errors::AppError;
+use crate::goals::GoalProgress;
+use crate::migrations::MigrationReport;
+use crate::parser::Stats;
+use crate::tasks::Task;
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+
+use flate2::read::GzDecoder;
+use log::error;
+use serde_json::{from_slice, to_string};
+use std::fs::{
+    create_dir, create_dir_all, read, read_dir, remove_dir_all, remove_file, rename, File,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use geojson::GeoJson;
+
+pub const OUTPUT_FILE: &str = "output.json";
+pub const OVERPASS_FILE: &str = "overpass.xml";
+pub const RUNS_DIR: &str = "runs";
+pub const TASKS_FILE: &str = "tasks.json";
+/// Suffix of artifacts compressed by `mc2 gc`.
+pub const GZIP_SUFFIX: &str = ".gz";
+
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Metadata of a single campaign run, stored next to its results.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Run {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub refresh: bool,
+    pub stats: Option<Stats>,
+    #[serde(default)]
+    pub goals: Vec<GoalProgress>,
+    /// Why the run failed, none while running or once it succeeded.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Run {
+    pub fn finish(self, stats: Stats, goals: Vec<GoalProgress>) -> Self {
+        Run {
+            finished_at: Some(Utc::now()),
+            stats: Some(stats),
+            goals,
+            ..self
+        }
+    }
+
+    pub fn fail(self, error: String) -> Self {
+        Run {
+            finished_at: Some(Utc::now()),
+            error: Some(error),
+            ..self
+        }
+    }
+
+    /// Whether the run finished with results.
+    pub fn succeeded(&self) -> bool {
+        self.finished_at.is_some() && self.error.is_none()
+    }
+}
+
+/// Results served from a file on disk, or fetched by the client from a URL.
+pub enum ResultsLocation {
+    File(PathBuf),
+    /// A file compressed by `mc2 gc`, served as is with gzip encoding.
+    Gzip(PathBuf),
+    Url(String),
+}
+
+/// Where campaigns, runs and their artifacts are kept. Runs write their files
+/// under `root` first, and the Overpass cache always stays there.
+pub trait Storage: Send + Sync {
+    fn root(&self) -> &Path;
+
+    /// Stores a new campaign and returns its uuid.
+    fn save_campaign(&self, campaign: Campaign) -> Result<String, AppError>;
+
+    fn load_campaign(&self, uuid: &str) -> Result<Campaign, AppError>;
+
+    /// Loads a campaign, applies `change` and saves the result as its next
+    /// version, with no other write to the campaign in between. Fails with
+    /// `StaleVersion` when `version` is given and no longer current. `change`
+    /// may be applied again when a backend retries a conflicting write.
+    fn modify_campaign(
+        &self,
+        uuid: &str,
+        version: Option<u64>,
+        change: Box<dyn Fn(Campaign) -> Campaign + '_>,
+    ) -> Result<Campaign, AppError>;
+
+    fn delete_campaign(&self, uuid: &str) -> Result<(), AppError>;
+
+    /// Uuids of every stored campaign, readable or not.
+    fn campaign_uuids(&self) -> Result<Vec<String>, AppError>;
+
+    /// Every readable campaign, with its geometry replaced by its centroid.
+    /// Unreadable ones are logged and left out.
+    fn list_campaigns(&self) -> Result<Vec<Campaign>, AppError>;
+
+    /// Page of the campaigns matching `query`, with the total number of matches.
+    fn query_campaigns(&self, query: &CampaignQuery) -> Result<CampaignPage, AppError>;
+
+    /// Rewrites every campaign stored with an older schema version.
+    fn migrate_campaigns(&self) -> Result<MigrationReport, AppError>;
+
+    fn save_run(&self, uuid: &str, run: &Run) -> Result<(), AppError>;
+
+    fn load_run(&self, uuid: &str, run: &str) -> Result<Run, AppError>;
+
+    /// Runs of a campaign, oldest first.
+    fn list_runs(&self, uuid: &str) -> Result<Vec<Run>, AppError>;
+
+    /// Removes a run with its files, returns the bytes freed.
+    fn delete_run(&self, uuid: &str, run: &str) -> Result<u64, AppError>;
+
+    /// Replaces the editable fields of a campaign, as long as it is still at
+    /// `version`. Identity, members, run state and progress stay as stored.
+    fn update_campaign(
+        &self,
+        uuid: &str,
+        version: Option<u64>,
+        new_campaign: Campaign,
+    ) -> Result<Campaign, AppError> {
+        self.modify_campaign(
+            uuid,
+            version,
+            Box::new(|old_campaign| {
+                let new_campaign = new_campaign.clone();
+
+                Campaign {
+                    name: new_campaign.name,
+                    geometry_types: new_campaign.geometry_types,
+                    tags: new_campaign.tags,
+                    geom: new_campaign.geom,
+                    description: new_campaign.description,
+                    organisation: new_campaign.organisation,
+                    hashtags: new_campaign.hashtags,
+                    visibility: new_campaign.visibility,
+                    categories: new_campaign.categories,
+                    goals: new_campaign.goals,
+                    task_grid: new_campaign.task_grid,
+                    source: new_campaign.source,
+                    start_date: new_campaign.start_date,
+                    end_date: new_campaign.end_date,
+                    run_interval_hours: new_campaign.run_interval_hours,
+                    ..old_campaign
+                }
+            }),
+        )
+    }
+
+    fn is_campaign_running(&self, uuid: &str) -> bool {
+        let campaign = match self.load_campaign(uuid) {
+            Ok(c) => c,
+            Err(err) => {
+                error!("{}", err.to_string());
+                return false;
+            }
+        };
+        !matches!(
+            campaign.status,
+            Some(Status::Finished) | Some(Status::Failed) | None
+        )
+    }
+
+    fn run_path(&self, uuid: &str, run: &str) -> PathBuf {
+        self.root().join(uuid).join(RUNS_DIR).join(run)
+    }
+
+    /// Creates the folder of a new run, named after its start time to the
+    /// millisecond. A run started in the same millisecond as another takes the
+    /// next free one.
+    fn create_run(&self, uuid: &str, refresh: bool) -> Result<Run, AppError> {
+        let mut started_at = Utc::now();
+        let id = loop {
+            let id = started_at.format("%Y%m%dT%H%M%S%3fZ").to_string();
+            let path = self.run_path(uuid, &id);
+            if let Some(runs) = path.parent() {
+                create_dir_all(runs)?;
+            }
+
+            match create_dir(&path) {
+                Ok(()) => break id,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    started_at = started_at + Duration::milliseconds(1)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let run = Run {
+            id,
+            started_at,
+            finished_at: None,
+            refresh,
+            stats: None,
+            goals: Vec::new(),
+            error: None,
+        };
+        self.save_run(uuid, &run)?;
+
+        Ok(run)
+    }
+
+    /// Latest finished run, the one results default to.
+    fn latest_run(&self, uuid: &str) -> Result<Option<Run>, AppError> {
+        let runs = self.list_runs(uuid)?;
+
+        Ok(runs.into_iter().rev().find(|r| r.succeeded()))
+    }
+
+    /// File kept next to the results of a run, or of the campaign itself when
+    /// `run` is none.
+    fn artifact_path(&self, uuid: &str, run: Option<&str>, name: &str) -> PathBuf {
+        match run {
+            Some(run) => self.run_path(uuid, run).join(name),
+            None => self.root().join(uuid).join(name),
+        }
+    }
+
+    fn read_artifact(
+        &self,
+        uuid: &str,
+        run: Option<&str>,
+        name: &str,
+    ) -> Result<Vec<u8>, AppError> {
+        Ok(read(self.artifact_path(uuid, run, name))?)
+    }
+
+    fn write_artifact(
+        &self,
+        uuid: &str,
+        run: Option<&str>,
+        name: &str,
+        contents: &[u8],
+    ) -> Result<(), AppError> {
+        let path = self.artifact_path(uuid, run, name);
+        create_dir_all(path.parent().unwrap())?;
+
+        write_atomic(&path, contents)
+    }
+
+    /// Removes an artifact, returns its size.
+    fn delete_artifact(&self, uuid: &str, run: Option<&str>, name: &str) -> Result<u64, AppError> {
+        let path = self.artifact_path(uuid, run, name);
+        if !path.is_file() {
+            return Err(AppError::NotFound);
+        }
+
+        let size = path.metadata()?.len();
+        remove_file(path)?;
+
+        Ok(size)
+    }
+
+    /// Contents of an artifact, uncompressed if `mc2 gc` gzipped it.
+    fn load_artifact(
+        &self,
+        uuid: &str,
+        run: Option<&str>,
+        name: &str,
+    ) -> Result<Vec<u8>, AppError> {
+        let compressed = match self.read_artifact(uuid, run, name) {
+            Err(AppError::NotFound) => {
+                self.read_artifact(uuid, run, &format!("{}{}", name, GZIP_SUFFIX))?
+            }
+            other => return other,
+        };
+
+        let mut contents = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut contents)?;
+
+        Ok(contents)
+    }
+
+    /// Moves the files a run wrote under `run_path` to where the backend keeps
+    /// its artifacts. Backends on the local disk leave them there.
+    fn store_run_files(&self, _uuid: &str, _run: &str) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    /// Run whose results are asked for, the latest finished one by default.
+    /// Campaigns computed before run history was kept only have top level
+    /// results, and no run.
+    fn results_run(&self, uuid: &str, run: Option<&str>) -> Result<Option<String>, AppError> {
+        let run = match run {
+            Some(id) => Some(self.load_run(uuid, id)?),
+            None => self.latest_run(uuid)?,
+        };
+
+        match run {
+            Some(r) if r.succeeded() => Ok(Some(r.id)),
+            Some(_r) => Err(AppError::NotFound),
+            None => Ok(None),
+        }
+    }
+
+    /// Where the results of a run can be served from.
+    fn results_location(&self, uuid: &str, run: Option<&str>) -> Result<ResultsLocation, AppError> {
+        let run = self.results_run(uuid, run)?;
+        let path = self.artifact_path(uuid, run.as_deref(), OUTPUT_FILE);
+        let compressed = self.artifact_path(
+            uuid,
+            run.as_deref(),
+            &format!("{}{}", OUTPUT_FILE, GZIP_SUFFIX),
+        );
+
+        match (path.is_file(), compressed.is_file()) {
+            (true, _) => Ok(ResultsLocation::File(path)),
+            (false, true) => Ok(ResultsLocation::Gzip(compressed)),
+            (false, false) => Err(AppError::NotFound),
+        }
+    }
+
+    fn load_results(&self, uuid: &str, run: Option<&str>) -> Result<GeoJson, AppError> {
+        let run = self.results_run(uuid, run)?;
+        let contents = self.load_artifact(uuid, run.as_deref(), OUTPUT_FILE)?;
+
+        let results: GeoJson =
+            from_slice(&contents).map_err(|err| AppError::SerdeError(err.to_string()))?;
+
+        Ok(results)
+    }
+
+    fn save_tasks(&self, uuid: &str, run: &str, tasks: &[Task]) -> Result<(), AppError> {
+        self.write_artifact(uuid, Some(run), TASKS_FILE, to_string(tasks)?.as_bytes())
+    }
+
+    /// Tasks of the given run, of the latest finished run otherwise.
+    fn load_tasks(&self, uuid: &str, run: Option<&str>) -> Result<Vec<Task>, AppError> {
+        let run = match run {
+            Some(id) => self.load_run(uuid, id)?,
+            None => self.latest_run(uuid)?.ok_or(AppError::NotFound)?,
+        };
+
+        let contents = self.load_artifact(uuid, Some(&run.id), TASKS_FILE)?;
+        let tasks: Vec<Task> = from_slice(&contents)?;
+
+        Ok(tasks)
+    }
+}
+
+/// Writes `contents` next to `path` and renames it into place, so readers see
+/// either the old file or the new one, never a partial write.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), AppError> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = path.with_file_name(name);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Removes a folder, returns the size of the files it held.
+pub fn remove_folder(path: &Path) -> Result<u64, AppError> {
+    fn size(path: &Path) -> u64 {
+        match read_dir(path) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| match entry.file_type() {
+                    Ok(t) if t.is_dir() => size(&entry.path()),
+                    _ => entry.metadata().map(|m| m.len()).unwrap_or(0),
+                })
+                .sum(),
+            Err(_e) => 0,
+        }
+    }
+
+    let freed = size(path);
+    remove_dir_all(path)?;
+
+    Ok(freed)
+}
+
+/// Storage shared by the server, the scheduler and campaign runs.
+pub type SharedStorage = Arc<dyn Storage>;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Backend {
+    File,
+    Sqlite,
+    S3,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(Backend::File),
+            "sqlite" => Ok(Backend::Sqlite),
+            "s3" => Ok(Backend::S3),
+            _ => Err(format!(
+                "Unknown backend {}, expected file, sqlite or s3",
+                s
+            )),
+        }
+    }
+}
+
+/// Opens the storage folder with the given backend. The S3 one only keeps
+/// unfinished runs and the Overpass cache there, and reads its bucket from
+/// `MC2_S3_*` variables.
+pub fn open(backend: Backend, path: &Path) -> Result<SharedStorage, AppError> {
+    let storage: SharedStorage = match backend {
+        Backend::File => Arc::new(LocalStorage::new(path)),
+        Backend::Sqlite => Arc::new(SqliteStorage::new(path)?),
+        Backend::S3 => Arc::new(S3Storage::new(path, S3Config::from_env()?)?),
+    };
+
+    Ok(storage)
+}
+
+/// Scratch storage folders for tests.
+#[cfg(test)]
+pub mod testing {
+    use super::s3::{S3Config, S3Storage};
+    use super::{fake_s3, SharedStorage};
+    use crate::campaign::Campaign;
+    use serde_json::{json, Value};
+    use std::fs::{remove_dir_all, File};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// A folder under the system temp dir, not created yet, removed on drop.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("mc2-test-{}-{}", std::process::id(), name));
+            let _ = remove_dir_all(&path);
+
+            TempDir(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = remove_dir_all(&self.0);
+        }
+    }
+
+    /// A bucket of its own on a fake S3 server.
+    pub fn fake_s3_config() -> S3Config {
+        S3Config {
+            endpoint: fake_s3::start(),
+            bucket: fake_s3::BUCKET.to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "test".to_string(),
+            secret_key: "test".to_string(),
+            prefix: "mc2".to_string(),
+        }
+    }
+
+    /// S3 storage on a fake server, runs write under `path` first.
+    pub fn fake_s3(path: &Path) -> SharedStorage {
+        Arc::new(S3Storage::new(path, fake_s3_config()).unwrap())
+    }
+
+    pub fn example_campaign() -> Campaign {
+        serde_json::from_reader(File::open("examples/campaign_example.json").unwrap()).unwrap()
+    }
+
+    /// Change renaming a campaign.
+    pub fn rename(name: &'static str) -> Box<dyn Fn(Campaign) -> Campaign> {
+        Box::new(move |c| Campaign {
+            name: name.to_string(),
+            ..c
+        })
+    }
+
+    /// The example campaign the way it was stored before schema versions.
+    pub fn unversioned_campaign() -> Value {
+        let mut document: Value =
+            serde_json::from_reader(File::open("examples/campaign_example.json").unwrap()).unwrap();
+        let fields = document.as_object_mut().unwrap();
+
+        fields.remove("schema_version");
+        fields.insert("hashtags".to_string(), Value::Null);
+        fields.remove("categories");
+        fields.insert(
+            "geometry_types".to_string(),
+            json!(["points", "linestrings"]),
+        );
+        fields.insert(
+            "tags".to_string(),
+            json!({"building": {"values": null, "secondary": {"levels": {}}}}),
+        );
+
+        document
+    }
+}
@@ -0,0 +1,362 @@
+use crate::campaign::{Campaign, Status, Visibility};
+
+use crate::errors::AppError;
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 500;
+/// Seconds `updated_at` may lag behind in an index. Runs save their progress
+/// every few seconds, the index only follows once a minute.
+const INDEX_UPDATE_SLACK: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortField {
+    Name,
+    CreatedAt,
+    UpdatedAt,
+}
+
+/// Order of a campaign listing, written `created_at` or `-created_at` for
+/// descending.
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub field: SortField,
+    pub descending: bool,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder {
+            field: SortField::CreatedAt,
+            descending: true,
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (descending, field) = match s.strip_prefix('-') {
+            Some(field) => (true, field),
+            None => (false, s),
+        };
+
+        let field = match field {
+            "name" => SortField::Name,
+            "created_at" => SortField::CreatedAt,
+            "updated_at" => SortField::UpdatedAt,
+            _ => {
+                return Err(format!(
+                    "Unknown sort {}, expected name, created_at or updated_at",
+                    s
+                ))
+            }
+        };
+
+        Ok(SortOrder { field, descending })
+    }
+}
+
+/// Filters and page of a campaign listing. Every filter is optional.
+#[derive(Debug, Clone)]
+pub struct CampaignQuery {
+    /// Only campaigns visible to this user id, public ones when none.
+    pub viewer: Option<i64>,
+    /// Campaigns whose bbox intersects min lon, min lat, max lon, max lat.
+    pub bbox: Option<(f64, f64, f64, f64)>,
+    pub status: Option<Status>,
+    pub owner: Option<i64>,
+    /// Case insensitive part of the name.
+    pub name: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    pub sort: SortOrder,
+    /// Starting at 1.
+    pub page: usize,
+    pub limit: usize,
+}
+
+impl Default for CampaignQuery {
+    fn default() -> Self {
+        CampaignQuery {
+            viewer: None,
+            bbox: None,
+            status: None,
+            owner: None,
+            name: None,
+            created_after: None,
+            created_before: None,
+            updated_after: None,
+            updated_before: None,
+            sort: SortOrder::default(),
+            page: 1,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl CampaignQuery {
+    pub fn offset(&self) -> usize {
+        (self.page.max(1) - 1) * self.limit
+    }
+}
+
+/// One page of matching campaigns, as centroids.
+#[derive(Serialize, Debug)]
+pub struct CampaignPage {
+    pub total: usize,
+    pub page: usize,
+    pub limit: usize,
+    pub campaigns: Vec<Campaign>,
+}
+
+/// What listings filter and sort campaigns on, kept in an index so they are
+/// answered without reading every campaign.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CampaignSummary {
+    pub uuid: String,
+    pub name: String,
+    pub status: Option<Status>,
+    pub owner: Option<i64>,
+    /// Owner and collaborators.
+    pub members: Vec<i64>,
+    pub visibility: Visibility,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub bbox: Option<(f64, f64, f64, f64)>,
+}
+
+/// Summaries of the stored campaigns by uuid, kept by backends without a
+/// database so listings don't read every campaign.
+pub type Index = BTreeMap<String, CampaignSummary>;
+
+/// Puts the entry of a campaign in the index, or removes it when `campaign`
+/// is none. Returns whether the index changed.
+pub fn update_index(index: &mut Index, uuid: &str, campaign: Option<&Campaign>) -> bool {
+    match campaign.map(CampaignSummary::new) {
+        Some(summary) if index.get(uuid).is_some_and(|s| summary.is_listed_as(s)) => false,
+        Some(summary) => {
+            index.insert(uuid.to_string(), summary);
+            true
+        }
+        None => index.remove(uuid).is_some(),
+    }
+}
+
+/// Answers `query` from an index, loading only the campaigns of the page.
+pub fn page_from_index(
+    index: Index,
+    query: &CampaignQuery,
+    load: impl Fn(&str) -> Result<Campaign, AppError>,
+) -> CampaignPage {
+    let mut matches = index
+        .into_values()
+        .filter(|summary| summary.matches(query))
+        .collect::<Vec<CampaignSummary>>();
+
+    matches.sort_by(|a, b| a.compare(b, query.sort));
+
+    let campaigns = matches
+        .iter()
+        .skip(query.offset())
+        .take(query.limit)
+        .filter_map(|summary| {
+            load(&summary.uuid)
+                .map_err(|e| warn!("Could not read campaign {} - {:?}", summary.uuid, e))
+                .ok()
+        })
+        .map(|campaign| campaign.centroid_as_geom())
+        .collect::<Vec<Campaign>>();
+
+    CampaignPage {
+        total: matches.len(),
+        page: query.page,
+        limit: query.limit,
+        campaigns,
+    }
+}
+
+impl CampaignSummary {
+    pub fn new(campaign: &Campaign) -> Self {
+        let owner = campaign.user.as_ref().map(|u| u.id);
+
+        CampaignSummary {
+            uuid: campaign.uuid.clone().unwrap_or_default(),
+            name: campaign.name.clone(),
+            status: campaign.status.clone(),
+            owner,
+            members: owner
+                .into_iter()
+                .chain(campaign.collaborators.iter().map(|c| c.user.id))
+                .collect(),
+            visibility: campaign.visibility,
+            created_at: campaign.created_at,
+            updated_at: campaign.updated_at,
+            bbox: campaign.bbox(),
+        }
+    }
+
+    /// Whether the index entry `stored` still lists the campaign as this
+    /// summary does.
+    fn is_listed_as(&self, stored: &CampaignSummary) -> bool {
+        let recent = match (self.updated_at, stored.updated_at) {
+            (Some(new), Some(old)) => new - old < Duration::seconds(INDEX_UPDATE_SLACK),
+            (new, old) => new == old,
+        };
+
+        recent
+            && CampaignSummary {
+                updated_at: stored.updated_at,
+                ..self.clone()
+            } == *stored
+    }
+
+    pub fn matches(&self, query: &CampaignQuery) -> bool {
+        let visible = self.visibility == Visibility::Public
+            || query.viewer.is_some_and(|v| self.members.contains(&v));
+
+        let in_bbox = query
+            .bbox
+            .is_none_or(|(min_lon, min_lat, max_lon, max_lat)| {
+                self.bbox.is_some_and(|(a, b, c, d)| {
+                    c >= min_lon && a <= max_lon && d >= min_lat && b <= max_lat
+                })
+            });
+
+        let in_range = |date: Option<DateTime<Utc>>,
+                        after: Option<DateTime<Utc>>,
+                        before: Option<DateTime<Utc>>| {
+            after.is_none_or(|a| date.is_some_and(|d| d >= a))
+                && before.is_none_or(|b| date.is_some_and(|d| d <= b))
+        };
+
+        visible
+            && in_bbox
+            && query
+                .status
+                .as_ref()
+                .is_none_or(|s| self.status.as_ref() == Some(s))
+            && query.owner.is_none_or(|o| self.owner == Some(o))
+            && query
+                .name
+                .as_ref()
+                .is_none_or(|n| self.name.to_lowercase().contains(&n.to_lowercase()))
+            && in_range(self.created_at, query.created_after, query.created_before)
+            && in_range(self.updated_at, query.updated_after, query.updated_before)
+    }
+
+    pub fn compare(&self, other: &Self, sort: SortOrder) -> Ordering {
+        let ordering = match sort.field {
+            SortField::Name => self.name.to_lowercase().cmp(&other.name.to_lowercase()),
+            SortField::CreatedAt => self.created_at.cmp(&other.created_at),
+            SortField::UpdatedAt => self.updated_at.cmp(&other.updated_at),
+        };
+
+        let ordering = match sort.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        };
+
+        // Keep pages stable between requests.
+        ordering.then_with(|| self.uuid.cmp(&other.uuid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::campaign::User;
+    use crate::storage::testing::{example_campaign, fake_s3, TempDir};
+    use crate::storage::{open, Backend, SharedStorage};
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    fn fill(storage: &SharedStorage) {
+        let statuses = [Status::Finished, Status::Running, Status::Failed];
+
+        for i in 0..7 {
+            let owner: User =
+                serde_json::from_value(json!({"name": "owner", "id": i % 2})).unwrap();
+            let campaign = Campaign {
+                name: format!("Campaign {}", (b'a' + (i * 3 % 7) as u8) as char),
+                created_at: Some(Utc.ymd(2020, 1, 1 + i as u32).and_hms(0, 0, 0)),
+                visibility: match i {
+                    4 => Visibility::Private,
+                    _ => Visibility::Public,
+                },
+                ..example_campaign().set_uuid()
+            };
+
+            storage
+                .save_campaign(campaign.set_user(owner).set_status(statuses[i % 3].clone()))
+                .unwrap();
+        }
+    }
+
+    fn listing(storage: &SharedStorage, query: &CampaignQuery) -> (usize, Vec<String>) {
+        let page = storage.query_campaigns(query).unwrap();
+        let names = page.campaigns.into_iter().map(|c| c.name).collect();
+
+        (page.total, names)
+    }
+
+    #[test]
+    fn test_backends_agree_on_listings() {
+        let dirs = ["file", "sqlite", "s3"].map(|b| TempDir::new(&format!("query-{}", b)));
+        let storages = [
+            open(Backend::File, dirs[0].path()).unwrap(),
+            open(Backend::Sqlite, dirs[1].path()).unwrap(),
+            fake_s3(dirs[2].path()),
+        ];
+        storages.iter().for_each(fill);
+
+        let queries = [
+            CampaignQuery::default(),
+            CampaignQuery {
+                viewer: Some(0),
+                sort: "name".parse().unwrap(),
+                ..Default::default()
+            },
+            CampaignQuery {
+                status: Some(Status::Finished),
+                sort: "-name".parse().unwrap(),
+                ..Default::default()
+            },
+            CampaignQuery {
+                owner: Some(1),
+                name: Some("CAMPAIGN".to_string()),
+                created_after: Some(Utc.ymd(2020, 1, 2).and_hms(0, 0, 0)),
+                sort: "created_at".parse().unwrap(),
+                ..Default::default()
+            },
+            CampaignQuery {
+                sort: "name".parse().unwrap(),
+                page: 2,
+                limit: 3,
+                ..Default::default()
+            },
+        ];
+
+        for query in queries.iter() {
+            let expected = listing(&storages[0], query);
+            assert!(!expected.1.is_empty(), "{:?} lists nothing", query);
+            for storage in storages[1..].iter() {
+                assert_eq!(listing(storage, query), expected, "{:?}", query);
+            }
+        }
+
+        let (total, names) = listing(&storages[0], &queries[4]);
+        assert_eq!(total, 6);
+        assert_eq!(names, ["Campaign d", "Campaign e", "Campaign g"]);
+    }
+}
@@ -0,0 +1,636 @@
+use super::query::SortField;
+use super::{remove_folder, CampaignPage, CampaignQuery, CampaignSummary, Run, SortOrder, Storage};
+use crate::campaign::{Campaign, Visibility};
+use crate::errors::AppError;
+use crate::migrations::{self, schema_version, upgrade, MigrationReport, SCHEMA_VERSION};
+
+use chrono::prelude::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension, ToSql, TransactionBehavior};
+use serde_json::{from_str, to_string, to_value, Value};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+const DATABASE_FILE: &str = "mc2.sqlite";
+/// Layout of the tables, kept in `PRAGMA user_version`.
+const DATABASE_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS campaigns (
+        uuid TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        status TEXT,
+        user_id INTEGER,
+        created_at TEXT,
+        updated_at TEXT,
+        min_lon REAL,
+        min_lat REAL,
+        max_lon REAL,
+        max_lat REAL,
+        visibility TEXT,
+        members TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS campaigns_bbox ON campaigns (min_lon, max_lon, min_lat, max_lat);
+    CREATE INDEX IF NOT EXISTS campaigns_status ON campaigns (status);
+    CREATE INDEX IF NOT EXISTS campaigns_user ON campaigns (user_id);
+    CREATE INDEX IF NOT EXISTS campaigns_created ON campaigns (created_at);
+    CREATE INDEX IF NOT EXISTS campaigns_updated ON campaigns (updated_at);
+    CREATE TABLE IF NOT EXISTS runs (
+        campaign TEXT NOT NULL,
+        id TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (campaign, id)
+    );
+";
+
+/// Campaigns and runs in an SQLite database at the storage root. The columns
+/// next to the JSON document are kept for filtering, the bbox ones indexed.
+pub struct SqliteStorage {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+/// Values of the filterable columns, in the order of the table.
+struct CampaignRow {
+    name: String,
+    status: Option<String>,
+    user_id: Option<i64>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+    bbox: Option<(f64, f64, f64, f64)>,
+    visibility: String,
+    /// Member ids between spaces, matched with LIKE '% id %'.
+    members: String,
+    data: String,
+}
+
+/// Dates as fixed width text, so they compare in SQL as they do in Rust.
+fn date_column(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+impl CampaignRow {
+    fn new(campaign: &Campaign) -> Result<Self, AppError> {
+        let status = match &campaign.status {
+            Some(s) => to_value(s)?.as_str().map(|s| s.to_string()),
+            None => None,
+        };
+
+        let summary = CampaignSummary::new(campaign);
+        let members = summary
+            .members
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>();
+
+        Ok(CampaignRow {
+            name: campaign.name.clone(),
+            status,
+            user_id: summary.owner,
+            created_at: campaign.created_at.map(date_column),
+            updated_at: campaign.updated_at.map(date_column),
+            bbox: summary.bbox,
+            visibility: match summary.visibility {
+                Visibility::Public => "public".to_string(),
+                Visibility::Private => "private".to_string(),
+            },
+            members: format!(" {} ", members.join(" ")),
+            data: to_string(campaign)?,
+        })
+    }
+}
+
+impl SqliteStorage {
+    pub fn new(storage: &Path) -> Result<Self, AppError> {
+        create_dir_all(storage)?;
+
+        let database = storage.join(DATABASE_FILE);
+        let connection = Connection::open(&database)?;
+        // Wait for writers from other processes instead of failing right away.
+        connection.busy_timeout(Duration::from_secs(10))?;
+        connection.execute_batch("PRAGMA journal_mode = WAL;")?;
+        connection.execute_batch(SCHEMA)?;
+        upgrade_database(&connection)?;
+
+        info!("Using database {}", database.display());
+
+        Ok(SqliteStorage {
+            path: storage.to_path_buf(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>, AppError> {
+        self.connection
+            .lock()
+            .map_err(|_e| AppError::DatabaseError("Database connection poisoned".to_string()))
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn root(&self) -> &Path {
+        &self.path
+    }
+
+    fn save_campaign(&self, campaign: Campaign) -> Result<String, AppError> {
+        let uuid = campaign.uuid.clone().unwrap();
+        let row = CampaignRow::new(&campaign)?;
+        let (min_lon, min_lat, max_lon, max_lat) = split_bbox(row.bbox);
+
+        self.connection()?.execute(
+            "INSERT INTO campaigns (uuid, name, status, user_id, created_at, updated_at,
+                min_lon, min_lat, max_lon, max_lat, visibility, members, data)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                uuid,
+                row.name,
+                row.status,
+                row.user_id,
+                row.created_at,
+                row.updated_at,
+                min_lon,
+                min_lat,
+                max_lon,
+                max_lat,
+                row.visibility,
+                row.members,
+                row.data
+            ],
+        )?;
+
+        Ok(uuid)
+    }
+
+    fn load_campaign(&self, uuid: &str) -> Result<Campaign, AppError> {
+        let data: String = self.connection()?.query_row(
+            "SELECT data FROM campaigns WHERE uuid = ?1",
+            params![uuid],
+            |row| row.get(0),
+        )?;
+
+        migrations::from_str(&data)
+    }
+
+    fn modify_campaign(
+        &self,
+        uuid: &str,
+        version: Option<u64>,
+        change: Box<dyn Fn(Campaign) -> Campaign + '_>,
+    ) -> Result<Campaign, AppError> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let data: String = transaction.query_row(
+            "SELECT data FROM campaigns WHERE uuid = ?1",
+            params![uuid],
+            |row| row.get(0),
+        )?;
+
+        let campaign = migrations::from_str(&data)?;
+        campaign.check_version(version)?;
+
+        let campaign = change(campaign).next_version();
+        update_campaign_row(&transaction, &campaign)?;
+        transaction.commit()?;
+
+        Ok(campaign)
+    }
+
+    fn delete_campaign(&self, uuid: &str) -> Result<(), AppError> {
+        let deleted = {
+            let connection = self.connection()?;
+            connection.execute("DELETE FROM runs WHERE campaign = ?1", params![uuid])?;
+            connection.execute("DELETE FROM campaigns WHERE uuid = ?1", params![uuid])?
+        };
+
+        if deleted == 0 {
+            return Err(AppError::NotFound);
+        }
+
+        let results = self.path.join(uuid);
+        if results.is_dir() {
+            std::fs::remove_dir_all(results)?;
+        }
+
+        Ok(())
+    }
+
+    fn campaign_uuids(&self) -> Result<Vec<String>, AppError> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare("SELECT uuid FROM campaigns ORDER BY uuid")?;
+
+        let uuids = statement
+            .query_map(params![], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(uuids)
+    }
+
+    fn list_campaigns(&self) -> Result<Vec<Campaign>, AppError> {
+        let connection = self.connection()?;
+        let mut statement =
+            connection.prepare("SELECT uuid, data FROM campaigns ORDER BY created_at")?;
+
+        let campaigns = statement
+            .query_map(params![], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| row.ok())
+            .filter_map(|(uuid, data)| {
+                migrations::from_str(&data)
+                    .map_err(|e| warn!("Could not read campaign {} - {}", uuid, e))
+                    .ok()
+            })
+            .map(|campaign| campaign.centroid_as_geom())
+            .collect::<Vec<Campaign>>();
+
+        Ok(campaigns)
+    }
+
+    fn query_campaigns(&self, query: &CampaignQuery) -> Result<CampaignPage, AppError> {
+        let connection = self.connection()?;
+        let (filters, mut values) = query_filters(query)?;
+
+        let total: i64 = connection.query_row(
+            &format!("SELECT COUNT(*) FROM campaigns {}", filters),
+            values.iter(),
+            |row| row.get(0),
+        )?;
+
+        values.push(Box::new(query.limit as i64));
+        values.push(Box::new(query.offset() as i64));
+
+        let mut statement = connection.prepare(&format!(
+            "SELECT uuid, data FROM campaigns {} {} LIMIT ? OFFSET ?",
+            filters,
+            order_by(query.sort)
+        ))?;
+
+        let campaigns = statement
+            .query_map(values.iter(), |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .filter_map(|row| row.ok())
+            .filter_map(|(uuid, data)| {
+                migrations::from_str(&data)
+                    .map_err(|e| warn!("Could not read campaign {} - {}", uuid, e))
+                    .ok()
+            })
+            .map(|campaign| campaign.centroid_as_geom())
+            .collect::<Vec<Campaign>>();
+
+        Ok(CampaignPage {
+            total: total as usize,
+            page: query.page,
+            limit: query.limit,
+            campaigns,
+        })
+    }
+
+    fn migrate_campaigns(&self) -> Result<MigrationReport, AppError> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut report = MigrationReport::default();
+
+        let rows = {
+            let mut statement =
+                transaction.prepare("SELECT uuid, data FROM campaigns ORDER BY uuid")?;
+            let rows = statement
+                .query_map(params![], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+            rows
+        };
+
+        for (uuid, data) in rows {
+            let migrated = from_str::<Value>(&data)
+                .map_err(AppError::from)
+                .and_then(|document| {
+                    let outdated = schema_version(&document) < SCHEMA_VERSION;
+                    let campaign = upgrade(document)?;
+                    if outdated {
+                        update_campaign_row(&transaction, &campaign)?;
+                    }
+                    Ok(outdated)
+                });
+
+            match migrated {
+                Ok(true) => report.migrated.push(uuid),
+                Ok(false) => report.current += 1,
+                Err(e) => report.unreadable.push((uuid, e.to_string())),
+            }
+        }
+
+        transaction.commit()?;
+
+        Ok(report)
+    }
+
+    fn save_run(&self, uuid: &str, run: &Run) -> Result<(), AppError> {
+        self.connection()?.execute(
+            "INSERT OR REPLACE INTO runs (campaign, id, data) VALUES (?1, ?2, ?3)",
+            params![uuid, run.id, to_string(run)?],
+        )?;
+
+        Ok(())
+    }
+
+    fn load_run(&self, uuid: &str, run: &str) -> Result<Run, AppError> {
+        let data: String = self.connection()?.query_row(
+            "SELECT data FROM runs WHERE campaign = ?1 AND id = ?2",
+            params![uuid, run],
+            |row| row.get(0),
+        )?;
+
+        let run: Run = from_str(&data)?;
+
+        Ok(run)
+    }
+
+    fn delete_run(&self, uuid: &str, run: &str) -> Result<u64, AppError> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM runs WHERE campaign = ?1 AND id = ?2",
+            params![uuid, run],
+        )?;
+        if deleted == 0 {
+            return Err(AppError::NotFound);
+        }
+
+        // Artifacts stay on disk next to the database.
+        let path = self.run_path(uuid, run);
+        match path.is_dir() {
+            true => remove_folder(&path),
+            false => Ok(0),
+        }
+    }
+
+    fn list_runs(&self, uuid: &str) -> Result<Vec<Run>, AppError> {
+        let connection = self.connection()?;
+
+        connection
+            .query_row(
+                "SELECT 1 FROM campaigns WHERE uuid = ?1",
+                params![uuid],
+                |_row| Ok(()),
+            )
+            .optional()?
+            .ok_or(AppError::NotFound)?;
+
+        let mut statement =
+            connection.prepare("SELECT data FROM runs WHERE campaign = ?1 ORDER BY id")?;
+
+        let runs = statement
+            .query_map(params![uuid], |row| row.get::<_, String>(0))?
+            .filter_map(|row| row.ok())
+            .filter_map(|data| {
+                from_str::<Run>(&data)
+                    .map_err(|e| warn!("Could not deserialize run of {} - {}", uuid, e))
+                    .ok()
+            })
+            .collect::<Vec<Run>>();
+
+        Ok(runs)
+    }
+}
+
+/// Overwrites the row of an existing campaign.
+fn update_campaign_row(connection: &Connection, campaign: &Campaign) -> Result<(), AppError> {
+    let uuid = campaign.uuid.clone().unwrap();
+    let row = CampaignRow::new(campaign)?;
+    let (min_lon, min_lat, max_lon, max_lat) = split_bbox(row.bbox);
+
+    let updated = connection.execute(
+        "UPDATE campaigns SET name = ?2, status = ?3, user_id = ?4, created_at = ?5,
+            updated_at = ?6, min_lon = ?7, min_lat = ?8, max_lon = ?9, max_lat = ?10,
+            visibility = ?11, members = ?12, data = ?13
+         WHERE uuid = ?1",
+        params![
+            uuid,
+            row.name,
+            row.status,
+            row.user_id,
+            row.created_at,
+            row.updated_at,
+            min_lon,
+            min_lat,
+            max_lon,
+            max_lat,
+            row.visibility,
+            row.members,
+            row.data
+        ],
+    )?;
+
+    match updated {
+        0 => Err(AppError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// Adds the columns of newer layouts to an existing database and fills them
+/// from the stored documents.
+fn upgrade_database(connection: &Connection) -> Result<(), AppError> {
+    let version: i64 = connection.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    if version >= DATABASE_VERSION {
+        return Ok(());
+    }
+
+    let columns = connection
+        .prepare("PRAGMA table_info(campaigns)")?
+        .query_map(params![], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+
+    if !columns.iter().any(|c| c == "members") {
+        info!("Adding listing columns to the campaigns table");
+        connection.execute_batch(
+            "ALTER TABLE campaigns ADD COLUMN visibility TEXT;
+             ALTER TABLE campaigns ADD COLUMN members TEXT;",
+        )?;
+    }
+
+    let rows = connection
+        .prepare("SELECT uuid, data FROM campaigns")?
+        .query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+
+    for (uuid, data) in rows {
+        match migrations::from_str(&data) {
+            Ok(campaign) => update_campaign_row(connection, &campaign)?,
+            Err(e) => warn!("Could not read campaign {} - {}", uuid, e),
+        }
+    }
+
+    connection.execute_batch(&format!("PRAGMA user_version = {}", DATABASE_VERSION))?;
+
+    Ok(())
+}
+
+/// WHERE clause and its values for the filters of `query`.
+fn query_filters(query: &CampaignQuery) -> Result<(String, Vec<Box<dyn ToSql>>), AppError> {
+    let mut conditions: Vec<&str> = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+
+    match query.viewer {
+        Some(viewer) => {
+            conditions.push("(visibility = 'public' OR members LIKE ?)");
+            values.push(Box::new(format!("% {} %", viewer)));
+        }
+        None => conditions.push("visibility = 'public'"),
+    }
+
+    if let Some((min_lon, min_lat, max_lon, max_lat)) = query.bbox {
+        conditions.push("max_lon >= ? AND min_lon <= ? AND max_lat >= ? AND min_lat <= ?");
+        values.push(Box::new(min_lon));
+        values.push(Box::new(max_lon));
+        values.push(Box::new(min_lat));
+        values.push(Box::new(max_lat));
+    }
+
+    if let Some(status) = &query.status {
+        conditions.push("status = ?");
+        values.push(Box::new(to_value(status)?.as_str().map(|s| s.to_string())));
+    }
+
+    if let Some(owner) = query.owner {
+        conditions.push("user_id = ?");
+        values.push(Box::new(owner));
+    }
+
+    if let Some(name) = &query.name {
+        let escaped = name
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        conditions.push("name LIKE ? ESCAPE '\\'");
+        values.push(Box::new(format!("%{}%", escaped)));
+    }
+
+    let dates = [
+        ("created_at >= ?", query.created_after),
+        ("created_at <= ?", query.created_before),
+        ("updated_at >= ?", query.updated_after),
+        ("updated_at <= ?", query.updated_before),
+    ];
+    for (condition, date) in dates.iter() {
+        if let Some(date) = date {
+            conditions.push(condition);
+            values.push(Box::new(date_column(*date)));
+        }
+    }
+
+    Ok((format!("WHERE {}", conditions.join(" AND ")), values))
+}
+
+fn order_by(sort: SortOrder) -> String {
+    let column = match sort.field {
+        SortField::Name => "name COLLATE NOCASE",
+        SortField::CreatedAt => "created_at",
+        SortField::UpdatedAt => "updated_at",
+    };
+    let direction = match sort.descending {
+        true => "DESC",
+        false => "ASC",
+    };
+
+    format!("ORDER BY {} {}, uuid", column, direction)
+}
+
+fn split_bbox(
+    bbox: Option<(f64, f64, f64, f64)>,
+) -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+    match bbox {
+        Some((min_lon, min_lat, max_lon, max_lat)) => {
+            (Some(min_lon), Some(min_lat), Some(max_lon), Some(max_lat))
+        }
+        None => (None, None, None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::{example_campaign, rename, unversioned_campaign, TempDir};
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new("sqlite-round-trip");
+        let storage = SqliteStorage::new(dir.path()).unwrap();
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        let saved = storage.load_campaign(&uuid).unwrap();
+        assert_eq!(saved.name, "Test Campaign");
+
+        let renamed = storage
+            .modify_campaign(&uuid, Some(saved.version), rename("Renamed"))
+            .unwrap();
+        assert_eq!(renamed.version, saved.version + 1);
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Renamed");
+
+        assert!(matches!(
+            storage.modify_campaign(&uuid, Some(saved.version), rename("Stale")),
+            Err(AppError::StaleVersion(v)) if v == renamed.version
+        ));
+        assert_eq!(storage.load_campaign(&uuid).unwrap().name, "Renamed");
+
+        let run = storage.create_run(&uuid, false).unwrap();
+        assert_eq!(storage.load_run(&uuid, &run.id).unwrap().id, run.id);
+        assert_eq!(storage.list_runs(&uuid).unwrap().len(), 1);
+
+        storage.delete_campaign(&uuid).unwrap();
+        assert!(matches!(
+            storage.load_campaign(&uuid),
+            Err(AppError::NotFound)
+        ));
+        assert!(storage.list_campaigns().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrates_unversioned_campaigns() {
+        let dir = TempDir::new("sqlite-migrate");
+        let storage = SqliteStorage::new(dir.path()).unwrap();
+
+        let uuid = storage
+            .save_campaign(example_campaign().set_uuid())
+            .unwrap();
+        let mut document = unversioned_campaign();
+        document["uuid"] = Value::from(uuid.as_str());
+        storage
+            .connection()
+            .unwrap()
+            .execute(
+                "UPDATE campaigns SET data = ?1 WHERE uuid = ?2",
+                params![to_string(&document).unwrap(), uuid],
+            )
+            .unwrap();
+
+        assert_eq!(
+            storage.load_campaign(&uuid).unwrap().geometry_types,
+            ["points", "lines"]
+        );
+
+        let report = storage.migrate_campaigns().unwrap();
+        assert_eq!(report.migrated, [uuid.as_str()]);
+        let data: String = storage
+            .connection()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM campaigns WHERE uuid = ?1",
+                params![uuid],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(schema_version(&from_str(&data).unwrap()), SCHEMA_VERSION);
+
+        let report = storage.migrate_campaigns().unwrap();
+        assert!(report.migrated.is_empty());
+        assert_eq!(report.current, 1);
+    }
+}
@@ -0,0 +1,526 @@
+use crate::diff::is_complete;
+use crate::errors::AppError;
+
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::algorithm::centroid::Centroid;
+use geo::algorithm::intersects::Intersects;
+use geo_types::{Coordinate, LineString, Point, Polygon};
+use geojson::{Feature, FeatureCollection, GeoJson, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const KM_PER_DEGREE: f64 = 111.32;
+pub const MAX_TASKS: f64 = 10_000.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GridShape {
+    Square,
+    Hex,
+}
+
+/// Cells campaign areas are split into, `size_km` wide.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TaskGrid {
+    pub shape: GridShape,
+    pub size_km: f64,
+}
+
+impl TaskGrid {
+    /// Number of cells laid out over the bounding box of `geom`, before those
+    /// outside its polygons are dropped.
+    pub fn cell_count(&self, geom: &GeoJson) -> Result<f64, AppError> {
+        let (grid, _polygons) = area_grid(self, geom)?;
+
+        Ok(grid.cell_count())
+    }
+}
+
+/// One cell of the grid with the counters of the features inside it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Task {
+    pub id: String,
+    pub geometry: geojson::Geometry,
+    pub features: i64,
+    pub feature_counts: HashMap<String, i64>,
+    pub completeness_count: HashMap<String, HashMap<String, i64>>,
+    /// Share of complete features, none when the task has no features.
+    pub completeness: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TaskDetail {
+    pub task: Task,
+    pub features: GeoJson,
+}
+
+/// Grid laid out in kilometres from the south west corner of the area, so
+/// cells keep their size away from the equator.
+struct Grid {
+    shape: GridShape,
+    size: f64,
+    origin: Coordinate<f64>,
+    kx: f64,
+    ky: f64,
+    /// Extent of the area in kilometres.
+    width: f64,
+    height: f64,
+}
+
+impl Grid {
+    fn new(task_grid: &TaskGrid, min: Coordinate<f64>, max: Coordinate<f64>) -> Self {
+        let latitude = (min.y + max.y) / 2.0;
+        let kx = KM_PER_DEGREE * latitude.to_radians().cos();
+
+        Grid {
+            shape: task_grid.shape,
+            size: task_grid.size_km,
+            origin: min,
+            kx,
+            ky: KM_PER_DEGREE,
+            width: (max.x - min.x) * kx,
+            height: (max.y - min.y) * KM_PER_DEGREE,
+        }
+    }
+
+    fn to_km(&self, c: Coordinate<f64>) -> (f64, f64) {
+        (
+            (c.x - self.origin.x) * self.kx,
+            (c.y - self.origin.y) * self.ky,
+        )
+    }
+
+    fn to_degrees(&self, x: f64, y: f64) -> Coordinate<f64> {
+        Coordinate {
+            x: self.origin.x + x / self.kx,
+            y: self.origin.y + y / self.ky,
+        }
+    }
+
+    /// Circumradius of the hexagons, `size` is the distance between opposite sides.
+    fn radius(&self) -> f64 {
+        self.size / 3f64.sqrt()
+    }
+
+    /// Columns and rows of cells covering the area. Hexagons get a ring more
+    /// around it, for those the box cuts. Floats, as tiny cells over a large
+    /// area overflow integers.
+    fn dimensions(&self) -> (f64, f64) {
+        match self.shape {
+            GridShape::Square => (
+                (self.width / self.size).ceil().max(1.0),
+                (self.height / self.size).ceil().max(1.0),
+            ),
+            GridShape::Hex => {
+                let r = self.radius();
+                (
+                    (self.width / (3f64.sqrt() * r)).ceil() + 3.0,
+                    (self.height / (1.5 * r)).ceil() + 3.0,
+                )
+            }
+        }
+    }
+
+    fn cell_count(&self) -> f64 {
+        let (cols, rows) = self.dimensions();
+
+        cols * rows
+    }
+
+    /// Cells covering the area, refused above `MAX_TASKS`.
+    fn cells(&self) -> Result<Vec<(i64, i64)>, AppError> {
+        match self.cell_count() {
+            n if n <= MAX_TASKS => (),
+            _ => {
+                return Err(AppError::RunError(format!(
+                    "The task grid needs more than {:.0} tasks",
+                    MAX_TASKS
+                )))
+            }
+        }
+
+        let (cols, rows) = self.dimensions();
+        let (cols, rows) = (cols as i64, rows as i64);
+
+        let cells = match self.shape {
+            GridShape::Square => (0..cols)
+                .flat_map(|i| (0..rows).map(move |j| (i, j)))
+                .collect(),
+            GridShape::Hex => (-1..rows - 1)
+                .flat_map(|row| (-1..cols - 1).map(move |col| (col - row.div_euclid(2), row)))
+                .collect(),
+        };
+
+        Ok(cells)
+    }
+
+    fn polygon(&self, (a, b): (i64, i64)) -> Polygon<f64> {
+        let corners: Vec<(f64, f64)> = match self.shape {
+            GridShape::Square => {
+                let (x, y) = (a as f64 * self.size, b as f64 * self.size);
+                vec![
+                    (x, y),
+                    (x + self.size, y),
+                    (x + self.size, y + self.size),
+                    (x, y + self.size),
+                ]
+            }
+            GridShape::Hex => {
+                let r = self.radius();
+                let cx = 3f64.sqrt() * r * (a as f64 + b as f64 / 2.0);
+                let cy = 1.5 * r * b as f64;
+
+                (0..6)
+                    .map(|i| {
+                        let angle = (60.0 * i as f64 - 30.0).to_radians();
+                        (cx + r * angle.cos(), cy + r * angle.sin())
+                    })
+                    .collect()
+            }
+        };
+
+        let mut ring: Vec<Coordinate<f64>> = corners
+            .into_iter()
+            .map(|(x, y)| self.to_degrees(x, y))
+            .collect();
+        ring.push(ring[0]);
+
+        Polygon::new(LineString(ring), Vec::new())
+    }
+
+    fn cell_of(&self, c: Coordinate<f64>) -> (i64, i64) {
+        let (x, y) = self.to_km(c);
+
+        match self.shape {
+            GridShape::Square => (
+                (x / self.size).floor() as i64,
+                (y / self.size).floor() as i64,
+            ),
+            GridShape::Hex => {
+                let r = self.radius();
+                let q = (3f64.sqrt() / 3.0 * x - y / 3.0) / r;
+                let s = (2.0 / 3.0 * y) / r;
+
+                hex_round(q, s)
+            }
+        }
+    }
+}
+
+/// Nearest hexagon to fractional axial coordinates.
+fn hex_round(q: f64, r: f64) -> (i64, i64) {
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+
+    (rq as i64, rr as i64)
+}
+
+fn task_id((a, b): (i64, i64)) -> String {
+    format!("{}_{}", a, b)
+}
+
+/// Point a feature is assigned to a task by, the centroid of lines and polygons.
+fn feature_point(feature: &Feature) -> Option<Point<f64>> {
+    let value = feature.geometry.as_ref()?.value.clone();
+
+    match value {
+        Value::Point(ref p) if p.len() >= 2 => Some(Point::new(p[0], p[1])),
+        Value::LineString(_) => TryInto::<LineString<f64>>::try_into(value).ok()?.centroid(),
+        Value::Polygon(_) => TryInto::<Polygon<f64>>::try_into(value).ok()?.centroid(),
+        _ => None,
+    }
+}
+
+fn area_polygons(geom: &GeoJson) -> Result<Vec<Polygon<f64>>, AppError> {
+    let collection = geojson::quick_collection(geom)
+        .map_err(|e| AppError::SerdeError(format!("Invalid campaign geometry {}", e)))?;
+
+    Ok(collection
+        .into_iter()
+        .filter_map(|g| match g {
+            geo_types::Geometry::Polygon(p) => Some(p),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Grid over the bounding box of `geom`, with the polygons of `geom`.
+fn area_grid(task_grid: &TaskGrid, geom: &GeoJson) -> Result<(Grid, Vec<Polygon<f64>>), AppError> {
+    let polygons = area_polygons(geom)?;
+
+    let (min, max) = polygons
+        .iter()
+        .filter_map(|p| p.bounding_rect())
+        .fold(
+            None,
+            |acc: Option<(Coordinate<f64>, Coordinate<f64>)>, rect| {
+                let (min, max) = acc.unwrap_or((rect.min(), rect.max()));
+                Some((
+                    Coordinate {
+                        x: min.x.min(rect.min().x),
+                        y: min.y.min(rect.min().y),
+                    },
+                    Coordinate {
+                        x: max.x.max(rect.max().x),
+                        y: max.y.max(rect.max().y),
+                    },
+                ))
+            },
+        )
+        .ok_or_else(|| AppError::SerdeError("Campaign geometry is empty".to_string()))?;
+
+    Ok((Grid::new(task_grid, min, max), polygons))
+}
+
+/// Splits `geom` into tasks and counts the features of `results` in each one.
+pub fn build_tasks(
+    task_grid: &TaskGrid,
+    geom: &GeoJson,
+    results: GeoJson,
+) -> Result<Vec<Task>, AppError> {
+    let (grid, polygons) = area_grid(task_grid, geom)?;
+
+    let mut tasks: HashMap<(i64, i64), Task> = grid
+        .cells()?
+        .into_iter()
+        .map(|cell| (cell, grid.polygon(cell)))
+        .filter(|(_cell, cell_polygon)| polygons.iter().any(|p| p.intersects(cell_polygon)))
+        .map(|(cell, cell_polygon)| {
+            let task = Task {
+                id: task_id(cell),
+                geometry: geojson::Geometry::new(Value::from(&cell_polygon)),
+                features: 0,
+                feature_counts: HashMap::new(),
+                completeness_count: HashMap::new(),
+                completeness: None,
+            };
+            (cell, task)
+        })
+        .collect();
+
+    let features = match results {
+        GeoJson::FeatureCollection(c) => c.features,
+        _ => Vec::new(),
+    };
+
+    let mut complete: HashMap<(i64, i64), i64> = HashMap::new();
+
+    features.iter().for_each(|feature| {
+        let (cell, task) = match feature_point(feature)
+            .map(|p| grid.cell_of(p.0))
+            .and_then(|cell| tasks.get_mut(&cell).map(|t| (cell, t)))
+        {
+            Some(t) => t,
+            None => return,
+        };
+
+        task.features += 1;
+        if is_complete(feature) {
+            *complete.entry(cell).or_insert(0) += 1;
+        }
+
+        feature
+            .properties
+            .as_ref()
+            .and_then(|p| p.get("stats"))
+            .and_then(|s| s.as_object())
+            .into_iter()
+            .flatten()
+            .for_each(|(key, tag_errors)| {
+                *task.feature_counts.entry(key.clone()).or_insert(0) += 1;
+
+                let field = match tag_errors
+                    .get("completeness")
+                    .and_then(|c| c.as_f64())
+                    .is_none_or(|c| c >= 1.0)
+                {
+                    true => "complete",
+                    false => "incomplete",
+                };
+
+                *task
+                    .completeness_count
+                    .entry(key.clone())
+                    .or_default()
+                    .entry(field.to_string())
+                    .or_insert(0) += 1;
+            });
+    });
+
+    let mut tasks: Vec<Task> = tasks
+        .into_iter()
+        .map(|(cell, task)| Task {
+            completeness: match task.features {
+                0 => None,
+                n => Some(*complete.get(&cell).unwrap_or(&0) as f64 / n as f64),
+            },
+            ..task
+        })
+        .collect();
+
+    tasks.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(tasks)
+}
+
+/// Features of `results` that `build_tasks` counted in `task`, for a campaign
+/// split by `task_grid` over `geom`.
+pub fn task_features(
+    task_grid: &TaskGrid,
+    geom: &GeoJson,
+    task: &Task,
+    results: GeoJson,
+) -> Result<GeoJson, AppError> {
+    let (grid, _polygons) = area_grid(task_grid, geom)?;
+
+    let features = match results {
+        GeoJson::FeatureCollection(c) => c.features,
+        _ => Vec::new(),
+    };
+
+    let features = features
+        .into_iter()
+        .filter(|f| feature_point(f).is_some_and(|p| task_id(grid.cell_of(p.0)) == task.id))
+        .collect();
+
+    Ok(GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Square degrees from `(x, y)` to `(x + side, y + side)`.
+    fn square(x: f64, y: f64, side: f64) -> serde_json::Value {
+        json!({
+            "type": "Feature",
+            "properties": {},
+            "geometry": {
+                "type": "Polygon",
+                "coordinates": [[[x, y], [x + side, y], [x + side, y + side], [x, y + side], [x, y]]]
+            }
+        })
+    }
+
+    fn area(squares: Vec<serde_json::Value>) -> GeoJson {
+        serde_json::from_value(json!({"type": "FeatureCollection", "features": squares})).unwrap()
+    }
+
+    fn points(points: &[(f64, f64)]) -> GeoJson {
+        let features = points
+            .iter()
+            .map(|(x, y)| {
+                json!({
+                    "type": "Feature",
+                    "properties": {},
+                    "geometry": {"type": "Point", "coordinates": [x, y]}
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::from_value(json!({"type": "FeatureCollection", "features": features})).unwrap()
+    }
+
+    fn grid(shape: GridShape, size_km: f64) -> TaskGrid {
+        TaskGrid { shape, size_km }
+    }
+
+    fn feature_count(features: GeoJson) -> usize {
+        match features {
+            GeoJson::FeatureCollection(c) => c.features.len(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_square_cells() {
+        // Just under 10 km a side at the equator.
+        let geom = area(vec![square(0.0, 0.0, 0.0898)]);
+        let grid = grid(GridShape::Square, 5.0);
+        assert_eq!(grid.cell_count(&geom).unwrap(), 4.0);
+
+        let tasks = build_tasks(&grid, &geom, points(&[])).unwrap();
+        let ids = tasks.iter().map(|t| t.id.as_str()).collect::<Vec<&str>>();
+        assert_eq!(ids, ["0_0", "0_1", "1_0", "1_1"]);
+        assert!(tasks
+            .iter()
+            .all(|t| t.features == 0 && t.completeness.is_none()));
+    }
+
+    #[test]
+    fn test_hex_cells() {
+        let geom = area(vec![square(0.0, 0.0, 0.0898)]);
+        let task_grid = grid(GridShape::Hex, 2.0);
+        let (hexes, _polygons) = area_grid(&task_grid, &geom).unwrap();
+
+        // Columns 2 km and rows 1.73 km apart, with a ring around them.
+        assert_eq!(hexes.dimensions(), (8.0, 9.0));
+        assert_eq!(task_grid.cell_count(&geom).unwrap(), 72.0);
+
+        // Every point lands in a task whose hexagon holds it.
+        let samples = (0..20)
+            .flat_map(|i| {
+                (0..20).map(move |j| (0.0898 * i as f64 / 19.0, 0.0898 * j as f64 / 19.0))
+            })
+            .collect::<Vec<(f64, f64)>>();
+        let tasks = build_tasks(&task_grid, &geom, points(&samples)).unwrap();
+        assert_eq!(
+            tasks.iter().map(|t| t.features).sum::<i64>(),
+            samples.len() as i64
+        );
+
+        for (x, y) in samples {
+            let cell = hexes.cell_of(Coordinate { x, y });
+            assert!(tasks.iter().any(|t| t.id == task_id(cell)));
+            let hexagon = hexes.polygon(cell).exterior().clone();
+            let inside = Polygon::new(hexagon, Vec::new()).intersects(&Point::new(x, y));
+            assert!(inside, "({}, {}) is outside hexagon {:?}", x, y, cell);
+        }
+    }
+
+    #[test]
+    fn test_features_belong_to_one_task() {
+        let geom = area(vec![square(0.0, 0.0, 0.0898)]);
+        let grid = grid(GridShape::Square, 5.0);
+
+        // On the line between the first two columns, and inside the first cell.
+        let (squares, _polygons) = area_grid(&grid, &geom).unwrap();
+        let boundary = squares.to_degrees(5.0, 1.0);
+        let results = points(&[(boundary.x, boundary.y), (0.01, 0.01)]);
+
+        let tasks = build_tasks(&grid, &geom, results.clone()).unwrap();
+        for task in &tasks {
+            let listed = feature_count(task_features(&grid, &geom, task, results.clone()).unwrap());
+            assert_eq!(listed as i64, task.features, "task {}", task.id);
+        }
+        assert_eq!(tasks.iter().map(|t| t.features).sum::<i64>(), 2);
+    }
+
+    #[test]
+    fn test_cells_are_bounded_by_the_bounding_box() {
+        // Two tiny areas far apart, 1000 km of bounding box for 100 m cells.
+        let geom = area(vec![square(0.0, 0.0, 0.001), square(9.0, 9.0, 0.001)]);
+
+        for shape in [GridShape::Square, GridShape::Hex].iter() {
+            let grid = grid(*shape, 0.1);
+            assert!(grid.cell_count(&geom).unwrap() > 1e7);
+            match build_tasks(&grid, &geom, points(&[])) {
+                Err(AppError::RunError(msg)) => assert!(msg.contains("more than")),
+                other => panic!("Expected too many tasks, got {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use crate::campaign::{Campaign, SearchTag};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{read_dir, File};
+use std::path::Path;
+
+const BUILTIN: &str = include_str!("../templates/builtin.json");
+
+/// A named set of tags and geometry types campaigns can start from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Template {
+    pub name: String,
+    pub description: Option<String>,
+    pub geometry_types: Vec<String>,
+    pub tags: HashMap<String, SearchTag>,
+}
+
+impl Template {
+    pub fn to_campaign(&self, name: String, geom: geojson::GeoJson) -> Campaign {
+        Campaign::new(name, self.geometry_types.clone(), self.tags.clone(), geom)
+    }
+}
+
+#[derive(Clone)]
+pub struct Templates {
+    templates: Vec<Template>,
+}
+
+impl Templates {
+    /// Built-in presets, plus one template per JSON file in `dir`. Templates
+    /// from the folder replace built-in ones with the same name.
+    pub fn load(dir: Option<&Path>) -> Self {
+        let mut templates: Vec<Template> =
+            serde_json::from_str(BUILTIN).expect("Built-in templates are not valid");
+
+        let custom = dir
+            .and_then(|d| {
+                read_dir(d)
+                    .map_err(|e| warn!("Could not read templates folder {} - {}", d.display(), e))
+                    .ok()
+            })
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                File::open(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|f| {
+                        serde_json::from_reader::<File, Template>(f).map_err(|e| e.to_string())
+                    })
+                    .map_err(|e| warn!("Skipping template {} - {}", path.display(), e))
+                    .ok()
+            })
+            .collect::<Vec<Template>>();
+
+        custom.into_iter().for_each(|template| {
+            info!("Loaded template {}", template.name);
+            templates.retain(|t| t.name != template.name);
+            templates.push(template);
+        });
+
+        Templates { templates }
+    }
+
+    pub fn list(&self) -> &Vec<Template> {
+        &self.templates
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.iter().find(|t| t.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::{example_campaign, TempDir};
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn test_presets_are_valid_campaigns() {
+        let templates = Templates::load(None);
+        assert!(!templates.list().is_empty());
+
+        for template in templates.list() {
+            let campaign = template.to_campaign(template.name.clone(), example_campaign().geom);
+
+            assert_eq!(campaign.tags.len(), template.tags.len());
+            assert!(
+                campaign.validate().is_ok(),
+                "Template {} is not valid - {:?}",
+                template.name,
+                campaign.validate()
+            );
+        }
+    }
+
+    #[test]
+    fn test_custom_templates() {
+        let dir = TempDir::new("templates-custom");
+        create_dir_all(dir.path()).unwrap();
+        let template = r#"{
+            "name": "buildings",
+            "description": "Buildings with their height",
+            "geometry_types": ["polygons"],
+            "tags": {"building": {"values": [], "secondary": {"height": {"values": []}}}}
+        }"#;
+        write(dir.path().join("buildings.json"), template).unwrap();
+        write(dir.path().join("broken.json"), "{").unwrap();
+        write(dir.path().join("notes.txt"), template).unwrap();
+
+        let builtin = Templates::load(None);
+        let templates = Templates::load(Some(dir.path()));
+
+        assert_eq!(templates.list().len(), builtin.list().len());
+        let buildings = templates.get("buildings").unwrap();
+        assert_eq!(
+            buildings.description.as_deref(),
+            Some("Buildings with their height")
+        );
+        assert!(templates.get("broken").is_none());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::campaign::{Campaign, TaskingManager};
+use crate::errors::AppError;
+use crate::templates::Templates;
+use crate::validation::FieldError;
+
+use geojson::{Feature, FeatureCollection, GeoJson, Geometry, Value};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TmProjectInfo {
+    pub name: Option<String>,
+    pub short_description: Option<String>,
+}
+
+/// The parts of a HOT Tasking Manager project export a campaign is built from.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TmProject {
+    pub project_id: i64,
+    pub area_of_interest: Geometry,
+    pub project_info: Option<TmProjectInfo>,
+    #[serde(default)]
+    pub mapping_types: Vec<String>,
+    pub changeset_comment: Option<String>,
+    pub organisation_name: Option<String>,
+}
+
+/// Template used for each Tasking Manager mapping type.
+fn template_name(mapping_type: &str) -> Option<&'static str> {
+    match mapping_type {
+        "BUILDINGS" => Some("buildings"),
+        "ROADS" => Some("roads"),
+        "WATERWAYS" => Some("waterways"),
+        "LAND_USE" => Some("land_use"),
+        _ => None,
+    }
+}
+
+/// Hashtags mentioned in a changeset comment, e.g. `#hotosm-project-1234`.
+fn hashtags(comment: &str) -> Vec<String> {
+    let mut hashtags: Vec<String> = Vec::new();
+
+    comment
+        .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+        .filter(|w| w.starts_with('#') && w.len() > 1)
+        .for_each(|w| {
+            if !hashtags.iter().any(|h| h == w) {
+                hashtags.push(w.to_string());
+            }
+        });
+
+    hashtags
+}
+
+/// Splits the area of interest into one polygon feature per polygon.
+fn area_of_interest(geometry: Geometry) -> Result<GeoJson, AppError> {
+    let polygons = match geometry.value {
+        Value::Polygon(p) => vec![p],
+        Value::MultiPolygon(mp) => mp,
+        _ => {
+            return Err(AppError::ValidationError(vec![FieldError::new(
+                "areaOfInterest",
+                "Area of interest must be a Polygon or MultiPolygon",
+            )]))
+        }
+    };
+
+    let features = polygons
+        .into_iter()
+        .map(|p| Feature {
+            bbox: None,
+            geometry: Some(Geometry::new(Value::Polygon(p))),
+            id: None,
+            properties: None,
+            foreign_members: None,
+        })
+        .collect();
+
+    Ok(GeoJson::FeatureCollection(FeatureCollection {
+        bbox: None,
+        features,
+        foreign_members: None,
+    }))
+}
+
+impl TmProject {
+    /// Reads a project export, an unreadable one is a validation error.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, AppError> {
+        serde_json::from_reader(reader).map_err(|e| match e.classify() {
+            serde_json::error::Category::Io => AppError::IOError(e.to_string()),
+            _ => AppError::ValidationError(vec![FieldError::new("project", &e.to_string())]),
+        })
+    }
+
+    pub fn into_campaign(self, templates: &Templates) -> Result<Campaign, AppError> {
+        let mut tags = HashMap::new();
+        let mut geometry_types: Vec<String> = Vec::new();
+
+        self.mapping_types
+            .iter()
+            .filter_map(|m| match template_name(m).and_then(|t| templates.get(t)) {
+                Some(template) => Some(template),
+                None => {
+                    warn!("Mapping type {} has no matching template", m);
+                    None
+                }
+            })
+            .for_each(|template| {
+                tags.extend(template.tags.clone());
+                template.geometry_types.iter().for_each(|t| {
+                    if !geometry_types.contains(t) {
+                        geometry_types.push(t.clone());
+                    }
+                });
+            });
+
+        let project_id = self.project_id;
+        let (name, description) = match self.project_info {
+            Some(info) => (info.name, info.short_description),
+            None => (None, None),
+        };
+        let name = name.unwrap_or_else(|| format!("Tasking Manager project {}", project_id));
+
+        let campaign = Campaign::new(
+            name,
+            geometry_types,
+            tags,
+            area_of_interest(self.area_of_interest)?,
+        );
+
+        let campaign = Campaign {
+            description,
+            organisation: self.organisation_name,
+            hashtags: self
+                .changeset_comment
+                .as_deref()
+                .map(hashtags)
+                .unwrap_or_default(),
+            ..campaign
+        };
+
+        Ok(campaign.set_tasking_manager(TaskingManager {
+            project_id,
+            changeset_comment: self.changeset_comment,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn import(contents: &str) -> Result<Campaign, AppError> {
+        TmProject::from_reader(contents.as_bytes())?.into_campaign(&Templates::load(None))
+    }
+
+    #[test]
+    fn test_project_export() {
+        let templates = Templates::load(None);
+        let project =
+            TmProject::from_reader(File::open("examples/tm_project.json").unwrap()).unwrap();
+
+        let campaign = project.into_campaign(&templates).unwrap();
+
+        assert_eq!(campaign.name, "Sierra Leone - Bo District Buildings");
+        assert_eq!(
+            campaign.description.as_deref(),
+            Some("Map buildings and roads around Bo.")
+        );
+        assert_eq!(campaign.organisation.as_deref(), Some("HOT"));
+        assert_eq!(
+            campaign.hashtags,
+            vec!["#hotosm-project-8172", "#MissingMaps", "#SierraLeone"]
+        );
+
+        let buildings = templates.get("buildings").unwrap();
+        let roads = templates.get("roads").unwrap();
+        let mut expected = buildings
+            .tags
+            .keys()
+            .chain(roads.tags.keys())
+            .collect::<Vec<_>>();
+        let mut tags = campaign.tags.keys().collect::<Vec<_>>();
+        expected.sort();
+        tags.sort();
+        assert_eq!(tags, expected);
+
+        match campaign.geom {
+            GeoJson::FeatureCollection(ref c) => {
+                assert_eq!(c.features.len(), 2);
+                assert!(c.features.iter().all(|f| matches!(
+                    f.geometry.as_ref().map(|g| &g.value),
+                    Some(Value::Polygon(_))
+                )));
+            }
+            ref other => panic!("Expected a feature collection, got {:?}", other),
+        }
+
+        let tm = campaign.tasking_manager.as_ref().unwrap();
+        assert_eq!(tm.project_id, 8172);
+        assert!(campaign.validate().is_ok());
+    }
+
+    #[test]
+    fn test_project_without_info() {
+        let campaign = import(
+            r#"{
+                "projectId": 12,
+                "areaOfInterest": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 0]]]},
+                "mappingTypes": ["WATERWAYS"]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(campaign.name, "Tasking Manager project 12");
+        assert!(campaign.hashtags.is_empty());
+        assert!(campaign.tags.contains_key("waterway"));
+    }
+
+    #[test]
+    fn test_malformed_exports() {
+        let exports = &[
+            "{",
+            "[]",
+            r#"{"projectId": 12}"#,
+            r#"{"projectId": "twelve", "areaOfInterest": {"type": "Polygon", "coordinates": []}}"#,
+            r#"{"projectId": 12, "areaOfInterest": {"type": "Point", "coordinates": [0, 0]}}"#,
+        ];
+
+        for export in exports {
+            match import(export) {
+                Err(AppError::ValidationError(errors)) => assert!(!errors.is_empty()),
+                other => panic!(
+                    "Expected a validation error for {}, got {:?}",
+                    export, other
+                ),
+            }
+        }
+    }
+}
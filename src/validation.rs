@@ -0,0 +1,397 @@
+use crate::campaign::{Campaign, SearchTag};
+use crate::goals::{Goal, Target};
+use crate::overpass::Overpass;
+use crate::source::Source;
+use crate::tasks::MAX_TASKS;
+
+use geojson::{GeoJson, Position, Value};
+use serde::Serialize;
+use std::collections::HashMap;
+
+pub const GEOMETRY_TYPES: [&str; 3] = ["points", "lines", "polygons"];
+pub const MAX_AREA_KM2: f64 = 10_000.0;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Campaign {
+    /// Checks the user supplied fields of a campaign, collecting every problem found.
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if self.name.trim().is_empty() {
+            errors.push(FieldError::new("name", "Name must not be empty"));
+        }
+
+        validate_geometry_types(&self.geometry_types, &mut errors);
+        validate_hashtags(&self.hashtags, &mut errors);
+        validate_categories(&self.categories, &mut errors);
+        validate_tags(&self.tags, &mut errors);
+        validate_goals(&self.goals, &self.tags, &mut errors);
+        validate_geom(&self.geom, &mut errors);
+
+        if let (Some(start), Some(end)) = (self.start_date, self.end_date) {
+            if end < start {
+                errors.push(FieldError::new(
+                    "end_date",
+                    "End date must be after the start date",
+                ));
+            }
+        }
+
+        if self.run_interval_hours.is_some_and(|h| h <= 0) {
+            errors.push(FieldError::new(
+                "run_interval_hours",
+                "Run interval must be at least one hour",
+            ));
+        }
+
+        if self.task_grid.as_ref().is_some_and(|g| g.size_km <= 0.0) {
+            errors.push(FieldError::new(
+                "task_grid.size_km",
+                "Task size must be above 0",
+            ));
+        }
+
+        if errors.is_empty() && self.area_km2() > MAX_AREA_KM2 {
+            errors.push(FieldError::new(
+                "geom",
+                &format!(
+                    "Area of {:.0} km2 is above the limit of {:.0} km2",
+                    self.area_km2(),
+                    MAX_AREA_KM2
+                ),
+            ));
+        }
+
+        if let Some(grid) = self.task_grid.as_ref().filter(|_g| errors.is_empty()) {
+            if grid.cell_count(&self.geom).is_ok_and(|n| n > MAX_TASKS) {
+                errors.push(FieldError::new(
+                    "task_grid.size_km",
+                    &format!(
+                        "Task size is too small, the area would need more than {:.0} tasks",
+                        MAX_TASKS
+                    ),
+                ));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Campaigns sent to the API may only fetch from Overpass, local files and
+    /// fixtures would let users publish any file the server can read.
+    pub fn validate_remote_source(&self) -> Result<(), Vec<FieldError>> {
+        match self.source {
+            None | Some(Source::Overpass) => Ok(()),
+            Some(_) => Err(vec![FieldError::new(
+                "source",
+                "Only Overpass sources can be set through the API",
+            )]),
+        }
+    }
+}
+
+fn validate_geometry_types(geometry_types: &[String], errors: &mut Vec<FieldError>) {
+    if geometry_types.is_empty() {
+        errors.push(FieldError::new(
+            "geometry_types",
+            "At least one geometry type is required",
+        ));
+    }
+
+    geometry_types
+        .iter()
+        .filter(|t| !GEOMETRY_TYPES.contains(&t.as_str()))
+        .for_each(|t| {
+            errors.push(FieldError::new(
+                "geometry_types",
+                &format!(
+                    "Unknown geometry type {}, expected one of {}",
+                    t,
+                    GEOMETRY_TYPES.join(", ")
+                ),
+            ))
+        });
+}
+
+fn validate_hashtags(hashtags: &[String], errors: &mut Vec<FieldError>) {
+    hashtags
+        .iter()
+        .enumerate()
+        .filter(|(_i, h)| !h.starts_with('#') || h.len() < 2 || h.contains(char::is_whitespace))
+        .for_each(|(i, h)| {
+            errors.push(FieldError::new(
+                &format!("hashtags[{}]", i),
+                &format!("{} must be a # followed by a word", h),
+            ))
+        });
+}
+
+fn validate_categories(categories: &[String], errors: &mut Vec<FieldError>) {
+    categories
+        .iter()
+        .enumerate()
+        .filter(|(_i, c)| c.trim().is_empty())
+        .for_each(|(i, _c)| {
+            errors.push(FieldError::new(
+                &format!("categories[{}]", i),
+                "Category must not be empty",
+            ))
+        });
+}
+
+fn validate_tags(tags: &HashMap<String, SearchTag>, errors: &mut Vec<FieldError>) {
+    if tags.is_empty() {
+        errors.push(FieldError::new("tags", "At least one tag is required"));
+    }
+
+    tags.iter().for_each(|(key, tag)| {
+        let field = format!("tags.{}", key);
+        if let Err(message) = Overpass::check_tag(key, &tag.values) {
+            errors.push(FieldError::new(&field, &message));
+        }
+
+        tag.secondary.iter().flatten().for_each(|(sk, st)| {
+            if let Err(message) = Overpass::check_tag(sk, &st.values) {
+                errors.push(FieldError::new(
+                    &format!("{}.secondary.{}", field, sk),
+                    &message,
+                ));
+            }
+        });
+    });
+}
+
+fn validate_goals(goals: &[Goal], tags: &HashMap<String, SearchTag>, errors: &mut Vec<FieldError>) {
+    goals.iter().enumerate().for_each(|(i, goal)| {
+        let field = format!("goals[{}]", i);
+
+        match (tags.get(&goal.tag), &goal.secondary) {
+            (None, _) => errors.push(FieldError::new(
+                &format!("{}.tag", field),
+                &format!("{} is not one of the campaign tags", goal.tag),
+            )),
+            (Some(tag), Some(secondary))
+                if !tag
+                    .secondary
+                    .as_ref()
+                    .is_some_and(|s| s.contains_key(secondary)) =>
+            {
+                errors.push(FieldError::new(
+                    &format!("{}.secondary", field),
+                    &format!("{} is not a secondary tag of {}", secondary, goal.tag),
+                ))
+            }
+            _ => (),
+        }
+
+        let message = match goal.target {
+            Target::Count(n) if n <= 0 => Some("Count must be at least one"),
+            Target::Percentage(p) if p <= 0.0 || p > 100.0 => {
+                Some("Percentage must be above 0 and at most 100")
+            }
+            _ => None,
+        };
+
+        if let Some(message) = message {
+            errors.push(FieldError::new(&format!("{}.target", field), message));
+        }
+    });
+}
+
+fn validate_geom(geom: &GeoJson, errors: &mut Vec<FieldError>) {
+    let feature_collection = match geom {
+        GeoJson::FeatureCollection(f) => f,
+        _ => {
+            errors.push(FieldError::new("geom", "Geojson must be FeatureCollection"));
+            return;
+        }
+    };
+
+    if feature_collection.features.is_empty() {
+        errors.push(FieldError::new(
+            "geom.features",
+            "At least one feature is required",
+        ));
+    }
+
+    feature_collection
+        .features
+        .iter()
+        .enumerate()
+        .for_each(|(i, feature)| {
+            let field = format!("geom.features[{}].geometry", i);
+
+            match feature.geometry.as_ref().map(|g| &g.value) {
+                Some(Value::Polygon(rings)) if rings.is_empty() => errors.push(FieldError::new(
+                    &format!("{}.coordinates", field),
+                    "Polygons need an outer ring",
+                )),
+                Some(Value::Polygon(rings)) => rings.iter().enumerate().for_each(|(j, ring)| {
+                    if let Err(message) = validate_ring(ring) {
+                        errors.push(FieldError::new(
+                            &format!("{}.coordinates[{}]", field, j),
+                            &message,
+                        ));
+                    }
+                }),
+                Some(_) => errors.push(FieldError::new(&field, "Polygon geometry supported only")),
+                None => errors.push(FieldError::new(&field, "Geometry not found")),
+            }
+        });
+}
+
+fn validate_ring(ring: &[Position]) -> Result<(), String> {
+    if ring.iter().any(|p| p.len() < 2) {
+        return Err("Positions need a longitude and a latitude".to_string());
+    }
+
+    if ring.len() < 4 {
+        return Err("Rings need at least four positions".to_string());
+    }
+
+    if ring.first() != ring.last() {
+        return Err("Ring is not closed".to_string());
+    }
+
+    match is_self_intersecting(ring) {
+        true => Err("Ring is self-intersecting".to_string()),
+        false => Ok(()),
+    }
+}
+
+fn orientation(a: &Position, b: &Position, c: &Position) -> f64 {
+    (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+}
+
+fn on_segment(a: &Position, b: &Position, p: &Position) -> bool {
+    p[0] >= a[0].min(b[0])
+        && p[0] <= a[0].max(b[0])
+        && p[1] >= a[1].min(b[1])
+        && p[1] <= a[1].max(b[1])
+}
+
+fn segments_intersect(a: &Position, b: &Position, c: &Position, d: &Position) -> bool {
+    let d1 = orientation(c, d, a);
+    let d2 = orientation(c, d, b);
+    let d3 = orientation(a, b, c);
+    let d4 = orientation(a, b, d);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+
+    (d1 == 0.0 && on_segment(c, d, a))
+        || (d2 == 0.0 && on_segment(c, d, b))
+        || (d3 == 0.0 && on_segment(a, b, c))
+        || (d4 == 0.0 && on_segment(a, b, d))
+}
+
+/// Compares every pair of non adjacent edges of a closed ring.
+fn is_self_intersecting(ring: &[Position]) -> bool {
+    let edges = ring.windows(2).collect::<Vec<&[Position]>>();
+    let count = edges.len();
+
+    (0..count).any(|i| {
+        (i + 1..count)
+            .filter(|j| j - i > 1 && !(i == 0 && *j == count - 1))
+            .any(|j| segments_intersect(&edges[i][0], &edges[i][1], &edges[j][0], &edges[j][1]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{GridShape, TaskGrid};
+    use std::fs::File;
+    use std::path::PathBuf;
+
+    fn example_campaign() -> Campaign {
+        serde_json::from_reader(File::open("examples/campaign_example.json").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_api_campaigns_only_use_overpass() {
+        let mut campaign = example_campaign();
+        assert!(campaign.validate().is_ok());
+        assert!(campaign.validate_remote_source().is_ok());
+
+        campaign.source = Some(Source::Overpass);
+        assert!(campaign.validate_remote_source().is_ok());
+
+        campaign.source = Some(Source::File {
+            path: PathBuf::from("/etc/passwd.osm"),
+        });
+        let errors = campaign.validate_remote_source().unwrap_err();
+        assert_eq!(errors[0].field, "source");
+
+        campaign.source = Some(Source::Fixtures {
+            dir: PathBuf::from("/"),
+        });
+        assert!(campaign.validate_remote_source().is_err());
+    }
+
+    #[test]
+    fn test_polygons_need_a_ring() {
+        let mut campaign = example_campaign();
+        campaign.geom = serde_json::from_str(
+            r#"{"type": "FeatureCollection", "features": [{"type": "Feature", "properties": {},
+                "geometry": {"type": "Polygon", "coordinates": []}}]}"#,
+        )
+        .unwrap();
+
+        let errors = campaign.validate().unwrap_err();
+        assert_eq!(errors[0].field, "geom.features[0].geometry.coordinates");
+        assert!(Overpass::new(campaign).is_err());
+    }
+
+    #[test]
+    fn test_task_grids_are_bounded_by_the_bounding_box() {
+        let square = |x: f64| {
+            format!(
+                r#"{{"type": "Feature", "properties": {{}}, "geometry": {{"type": "Polygon",
+                    "coordinates": [[[{x}, {x}], [{y}, {x}], [{y}, {y}], [{x}, {y}], [{x}, {x}]]]}}}}"#,
+                x = x,
+                y = x + 0.001
+            )
+        };
+        let mut campaign = example_campaign();
+        campaign.geom = serde_json::from_str(&format!(
+            r#"{{"type": "FeatureCollection", "features": [{}, {}]}}"#,
+            square(0.0),
+            square(9.0)
+        ))
+        .unwrap();
+        campaign.task_grid = Some(TaskGrid {
+            shape: GridShape::Square,
+            size_km: 0.1,
+        });
+
+        let errors = campaign.validate().unwrap_err();
+        assert_eq!(errors[0].field, "task_grid.size_km");
+
+        campaign.task_grid = Some(TaskGrid {
+            shape: GridShape::Hex,
+            size_km: 50.0,
+        });
+        assert!(campaign.validate().is_ok());
+    }
+}